@@ -0,0 +1,109 @@
+//! Benchmarks of the sort engine on a 12 megapixel image, measured against
+//! the engine before sort keys were cached.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{DynamicImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+use screen::bench::{
+    hue_key, pixel_map_column, pixel_map_row, pixel_unsort, sort_keys, sort_map_column,
+    sort_map_image, sort_map_row, ImgGrid, SortKeyFn, SortOrder,
+};
+
+fn bench_image() -> DynamicImage {
+    let mut rng = fastrand::Rng::with_seed(12);
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(4000, 3000, |_, _| {
+        Rgba([rng.u8(..), rng.u8(..), rng.u8(..), 255])
+    }))
+}
+
+// The row sort before keys were cached, calling the key function on every
+// comparison and one row at a time.
+fn legacy_pixel_map_row(img: &DynamicImage, f: &SortKeyFn, order: SortOrder) -> ImgGrid {
+    let mut px_map = ImgGrid::generate(img.width() as usize, img.height() as usize, |x, y| (x, y));
+    let key = |p: &(usize, usize)| {
+        let (x, y) = (p.0 as u32, p.1 as u32);
+        order.dir() * f(x, y, img.get_pixel(x, y))
+    };
+    for y in 0..px_map.height {
+        let mut row = px_map[y].to_vec();
+        row.par_sort_by(|a, b| key(a).total_cmp(&key(b)));
+        let mut indices = (0..row.len()).collect::<Vec<_>>();
+        indices.par_sort_by_key(|i| row[*i].0);
+        let row1 = indices.par_iter().map(|i| (*i, y)).collect::<Vec<_>>();
+        px_map[y].par_iter_mut().enumerate().for_each(|(i, e)| {
+            *e = row1[i];
+        });
+    }
+    px_map
+}
+
+// The column sort before keys were cached, gathering each column and
+// writing it back element by element.
+fn legacy_pixel_map_column(
+    img: &DynamicImage,
+    f: &SortKeyFn,
+    order: SortOrder,
+    mut px_map: ImgGrid,
+) -> ImgGrid {
+    let key = |p: &(usize, usize)| {
+        let (x, y) = (p.0 as u32, p.1 as u32);
+        order.dir() * f(x, y, img.get_pixel(x, y))
+    };
+    for x in 0..px_map.width {
+        let mut column = px_map.get_column(x);
+        column.par_sort_by(|a, b| key(a).total_cmp(&key(b)));
+        let mut indices = (0..column.len()).collect::<Vec<_>>();
+        indices.par_sort_by_key(|i| column[*i].1);
+        let column1 = indices.par_iter().map(|i| (x, *i)).collect::<Vec<_>>();
+        for (y, p) in column1.into_iter().enumerate() {
+            px_map[y][x] = p;
+        }
+    }
+    px_map
+}
+
+fn pixel_map_benches(c: &mut Criterion) {
+    let img = bench_image();
+    let f = hue_key();
+    let order = SortOrder::Ascending;
+    let legacy = || legacy_pixel_map_column(&img, &f, order, legacy_pixel_map_row(&img, &f, order));
+    let cached = || {
+        let keys = sort_keys(&img, &f);
+        let pm = pixel_map_row(&keys, order, None, None);
+        pixel_map_column(&keys, order, None, Some(pm))
+    };
+    assert_eq!(legacy(), cached());
+
+    let mut group = c.benchmark_group("pixel map row + column");
+    group.sample_size(10);
+    group.bench_function("legacy", |b| b.iter(&legacy));
+    group.bench_function("cached", |b| b.iter(&cached));
+    group.finish();
+}
+
+fn sort_map_benches(c: &mut Criterion) {
+    let img = bench_image();
+    let f = hue_key();
+    let order = SortOrder::Descending;
+    let keys = sort_keys(&img, &f);
+    let pm = sort_map_row(&keys, order, None, None);
+
+    let mut group = c.benchmark_group("sort maps");
+    group.sample_size(10);
+    group.bench_function("sort keys", |b| b.iter(|| sort_keys(&img, &f)));
+    group.bench_function("sort map row", |b| {
+        b.iter(|| sort_map_row(&keys, order, None, None))
+    });
+    group.bench_function("sort map column", |b| {
+        b.iter(|| sort_map_column(&keys, order, None, None))
+    });
+    group.bench_function("sort map image", |b| {
+        b.iter(|| sort_map_image(&keys, order))
+    });
+    group.bench_function("unsort", |b| b.iter(|| pixel_unsort(&img, &pm)));
+    group.bench_function("transpose", |b| b.iter(|| pm.transpose()));
+    group.finish();
+}
+
+criterion_group!(benches, pixel_map_benches, sort_map_benches);
+criterion_main!(benches);
@@ -0,0 +1,173 @@
+//! Export the sort as an animation, each frame moving the pixels part of the
+//! way from their original to their sorted positions.
+
+use crate::art::{overlay, partial_pixel_map, pixel_unsort, Overlay};
+use crate::core::{App, DitherKind, ImgGrid};
+use crate::dither::{dither, Quantizer};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, RgbaImage};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The image with its pixels moved `t` of the way along the pixel map.
+pub fn frame(src: &DynamicImage, px_map: &ImgGrid, t: f32) -> RgbaImage {
+    pixel_unsort(src, &partial_pixel_map(px_map, |_, _| t))
+}
+
+/// What an export takes from the app: the sort, and how the render quantized
+/// and overlaid it.
+pub struct Export {
+    px_map: Arc<ImgGrid>,
+    src: RgbaImage,
+    frames: u32,
+    pub delay: u32,
+    quantizer: Option<Quantizer>,
+    dither_kind: DitherKind,
+    dither_amount: f32,
+    overlay: Overlay,
+}
+
+impl Export {
+    pub fn new(app: &App, px_map: Arc<ImgGrid>, src: RgbaImage) -> Self {
+        Self {
+            px_map,
+            src,
+            frames: app.anim_frames.max(2),
+            delay: app.anim_delay,
+            quantizer: app.quantizer.clone().filter(|_| app.quantize),
+            dither_kind: app.dither_kind,
+            dither_amount: app.dither_amount,
+            overlay: Overlay::new(app),
+        }
+    }
+}
+
+/// The frames of the animation from the source image to the mapped image,
+/// quantized like the render and with its overlay drawn on each.
+pub fn frames(export: &Export) -> impl ExactSizeIterator<Item = RgbaImage> + '_ {
+    let src = DynamicImage::ImageRgba8(export.src.clone());
+    let n = export.frames;
+    (0..n).map(move |i| {
+        let img = frame(&src, &export.px_map, i as f32 / (n - 1) as f32);
+        let img = match &export.quantizer {
+            Some(q) => dither(&img, q, export.dither_kind, export.dither_amount),
+            None => img,
+        };
+        overlay(&export.overlay, img)
+    })
+}
+
+/// Save the frames as a gif or apng, or as a numbered png series for any
+/// other extension.
+pub fn save<I>(frames: I, path: &Path, delay_ms: u32) -> Result<(), Box<dyn Error>>
+where
+    I: ExactSizeIterator<Item = RgbaImage>,
+{
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gif") => save_gif(frames, path, delay_ms),
+        Some("apng") => save_apng(frames, path, delay_ms),
+        _ => save_series(frames, path),
+    }
+}
+
+fn save_gif<I>(frames: I, path: &Path, delay_ms: u32) -> Result<(), Box<dyn Error>>
+where
+    I: Iterator<Item = RgbaImage>,
+{
+    let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+    for img in frames {
+        encoder.encode_frame(Frame::from_parts(img, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+fn save_apng<I>(mut frames: I, path: &Path, delay_ms: u32) -> Result<(), Box<dyn Error>>
+where
+    I: ExactSizeIterator<Item = RgbaImage>,
+{
+    let count = frames.len() as u32;
+    let Some(first) = frames.next() else {
+        return Err("no frames to save".into());
+    };
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        first.width(),
+        first.height(),
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(count, 0)?;
+    encoder.set_frame_delay(delay_ms.min(u16::MAX as u32) as u16, 1000)?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(first.as_raw())?;
+    for img in frames {
+        writer.write_image_data(img.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+// Save each frame next to the path, numbered in order.
+fn save_series<I>(frames: I, path: &Path) -> Result<(), Box<dyn Error>>
+where
+    I: Iterator<Item = RgbaImage>,
+{
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    for (i, img) in frames.enumerate() {
+        img.save(path.with_file_name(format!("{}_{:04}.png", stem, i)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::Matrix;
+    use image::{AnimationDecoder, Rgba};
+
+    fn test_frames() -> Vec<RgbaImage> {
+        let src = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 4, |x, y| {
+            Rgba([x as u8 * 30, y as u8 * 60, 0, 255])
+        }));
+        let reversed = Matrix::generate(8, 4, |x, y| (7 - x, y));
+        (0..5)
+            .map(|i| frame(&src, &reversed, i as f32 / 4.0))
+            .collect()
+    }
+
+    #[test]
+    fn frame_test() {
+        let frames = test_frames();
+        assert_eq!(frames[0].get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_eq!(frames[4].get_pixel(0, 0), &Rgba([210, 0, 0, 255]));
+        assert_eq!(frames[2].get_pixel(1, 3), &Rgba([120, 180, 0, 255]));
+    }
+
+    #[test]
+    fn save_test() {
+        let dir = std::env::temp_dir().join(format!("mixel_anim_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let gif = dir.join("sort.gif");
+        save(test_frames().into_iter(), &gif, 50).unwrap();
+        let decoder =
+            image::codecs::gif::GifDecoder::new(std::io::BufReader::new(File::open(&gif).unwrap()))
+                .unwrap();
+        assert_eq!(decoder.into_frames().count(), 5);
+
+        let apng = dir.join("sort.apng");
+        save(test_frames().into_iter(), &apng, 50).unwrap();
+        let decoder = png::Decoder::new(File::open(&apng).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 5);
+
+        save(test_frames().into_iter(), &dir.join("sort.png"), 50).unwrap();
+        assert!(dir.join("sort_0004.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
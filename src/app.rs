@@ -1,13 +1,19 @@
+use crate::animate;
 use crate::art::draw;
 use crate::core::{
-    dims, to_color_image, App, BlendMode, Combine, LineColor, SortBy, SortKey, SortOrder,
+    dims, to_color_image, App, BlendMode, BlendSpace, CellLayout, Combine, DitherKind,
+    HistogramSpace, KeyKind, LineColor, MeltDirection, OpacityKind, OpacityMap, PaletteMapping,
+    PaletteMethod, QuantizePalette, SortBy, SortFeature, SortKey, SortOrder, SortTerm,
 };
-use egui::{Button, ComboBox, Frame, Grid, SliderClamping, Vec2};
+use crate::mapfile;
+use crate::swatches;
+use egui::{Button, ComboBox, Frame, Grid, SliderClamping, Ui, Vec2};
+use image::GrayImage;
 use serde_json;
 use std::{
     fs::File,
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread,
 };
@@ -50,6 +56,9 @@ impl App {
                     Default::default(),
                 ));
             }
+            app.load_map();
+            app.load_extra_images();
+            app.load_opacity_images();
             return app;
         }
 
@@ -77,6 +86,123 @@ impl App {
         Ok(app)
     }
 
+    // Load the pixel map saved at the map path for Remap, a map that can't be
+    // read is reported in the status line.
+    pub fn load_map(&mut self) {
+        self.loaded_map = None;
+        if let Some(path) = &self.map_path {
+            match mapfile::load(Path::new(path)) {
+                Ok(px_map) => self.loaded_map = Some(Arc::new(px_map)),
+                Err(e) => self.status_message = format!("Could Not Load Map {}: {}", path, e),
+            }
+        }
+    }
+
+    // Load the extra images that the pixel map is also applied to, dropping
+    // any that can't be read and naming them in the status line.
+    pub fn load_extra_images(&mut self) {
+        let mut imgs = Vec::new();
+        let mut failed = Vec::new();
+        self.extra_paths.retain(|path| match image::open(path) {
+            Ok(img) => {
+                imgs.push(img.to_rgba8());
+                true
+            }
+            Err(_) => {
+                failed.push(path.clone());
+                false
+            }
+        });
+        self.extra_imgs = imgs;
+        if !failed.is_empty() {
+            self.status_message = format!("Could Not Load Extra Images: {}", failed.join(", "));
+        }
+    }
+
+    // Load the grayscale images used as opacity maps.
+    pub fn load_opacity_images(&mut self) {
+        let load = |map: &OpacityMap| {
+            map.path
+                .as_ref()
+                .and_then(|path| image::open(path).ok())
+                .map(|i| i.to_luma8())
+        };
+        self.opacity_img_1 = load(&self.opacity_map_1);
+        self.opacity_img_2 = load(&self.opacity_map_2);
+    }
+
+    // Save the image, and each extra output next to it numbered in order.
+    pub fn save_images(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.img.save(path)?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        for (i, extra) in self.extra_outputs.iter().enumerate() {
+            extra.save(path.with_file_name(format!("{}_{}.{}", stem, i + 1, ext)))?;
+        }
+        Ok(())
+    }
+
+    // Export the frames from the source image to the current output, as a
+    // gif, apng or png series depending on the extension chosen, on a worker
+    // thread that reports each frame in the status line.
+    pub fn export_animation(&mut self, ctx: &egui::Context) {
+        if self.drawing_in_progress || self.export_receiver.is_some() {
+            self.status_message = "Wait For The Current Render".to_string();
+            return;
+        }
+        let (Some(px_map), Some(src)) = (&self.pixel_map, &self.map_source) else {
+            self.status_message = "No Sort To Animate".to_string();
+            return;
+        };
+        if (src.width() as usize, src.height() as usize) != (px_map.width, px_map.height) {
+            self.status_message = "Pixel Map Does Not Match The Image".to_string();
+            return;
+        }
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("gif", &["gif"])
+            .add_filter("apng", &["apng"])
+            .add_filter("png series", &["png"])
+            .save_file()
+        {
+            let path = if path.extension().is_none() {
+                path.with_extension("gif")
+            } else {
+                path
+            };
+            let export = animate::Export::new(self, px_map.clone(), src.clone());
+            let (tx, rx) = std::sync::mpsc::channel();
+            let (status_tx, status_rx) = std::sync::mpsc::channel();
+            let status_message = Arc::new(Mutex::new(String::new()));
+            let status_message_clone = status_message.clone();
+            self.export_receiver = Some(rx);
+            self.status_message_arc = Some(status_message);
+
+            let done_ctx = ctx.clone();
+            thread::spawn(move || {
+                let frames = animate::frames(&export);
+                let n = frames.len();
+                let frames = frames.enumerate().map(|(i, frame)| {
+                    let _ = status_tx.send(format!("Rendering Frame {} of {}", i + 1, n));
+                    frame
+                });
+                let result = animate::save(frames, &path, export.delay).map_err(|e| e.to_string());
+                let _ = tx.send(result);
+                done_ctx.request_repaint();
+            });
+
+            // Start a thread to receive status updates
+            let ctx = ctx.clone();
+            thread::spawn(move || {
+                while let Ok(msg) = status_rx.recv() {
+                    if let Ok(mut status) = status_message_clone.lock() {
+                        *status = msg;
+                        ctx.request_repaint();
+                    }
+                }
+            });
+        }
+    }
+
     pub fn reset(&mut self) {
         let mut app = App::default();
         app.img_path_1 = self.img_path_1.clone();
@@ -141,6 +267,9 @@ impl eframe::App for App {
                                     to_color_image(&thumb2, 200, 150),
                                     Default::default(),
                                 ));
+                                self.load_map();
+                                self.load_extra_images();
+                                self.load_opacity_images();
                             }
                             ui.close_menu();
                         }
@@ -153,7 +282,7 @@ impl eframe::App for App {
                         if ui.button("Save png").clicked() {
                             if let Some(path) = rfd::FileDialog::new().save_file() {
                                 let path = path.with_extension("png");
-                                self.img.save(&path).unwrap();
+                                self.save_images(&path).unwrap();
                                 println!("Image Saved");
                                 println!("-----------------------------");
                             }
@@ -162,12 +291,83 @@ impl eframe::App for App {
                         if ui.button("Save tiff").clicked() {
                             if let Some(path) = rfd::FileDialog::new().save_file() {
                                 let path = path.with_extension("tiff");
-                                self.img.save(&path).unwrap();
+                                self.save_images(&path).unwrap();
                                 println!("Image Saved");
                                 println!("-----------------------------");
                             }
                             ui.close_menu();
                         }
+                        if ui.button("Load map").clicked() {
+                            if let Some(path) = rfd::FileDialog::new()
+                                .add_filter("pixel map", &["pxmap", "png"])
+                                .pick_file()
+                            {
+                                self.map_path = Some(path.display().to_string());
+                                self.load_map();
+                            }
+                            ui.close_menu();
+                        }
+                        if ui.button("Save map").clicked() {
+                            if let Some(px_map) = &self.pixel_map {
+                                if let Some(path) = rfd::FileDialog::new().save_file() {
+                                    let path = path.with_extension("pxmap");
+                                    match mapfile::save(px_map, &path) {
+                                        Ok(()) => {
+                                            self.map_path = Some(path.display().to_string());
+                                            self.loaded_map = Some(px_map.clone());
+                                        }
+                                        Err(e) => {
+                                            self.status_message =
+                                                format!("Could Not Save Map: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                            ui.close_menu();
+                        }
+                        if ui.button("Save uv map").clicked() {
+                            if let Some(px_map) = &self.pixel_map {
+                                if let Some(path) = rfd::FileDialog::new().save_file() {
+                                    let path = path.with_extension("png");
+                                    match mapfile::save(px_map, &path) {
+                                        Ok(()) => {
+                                            self.map_path = Some(path.display().to_string());
+                                            self.loaded_map = Some(px_map.clone());
+                                        }
+                                        Err(e) => {
+                                            self.status_message =
+                                                format!("Could Not Save Map: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                            ui.close_menu();
+                        }
+                        if ui.button("Save palette").clicked() {
+                            if !self.palette.is_empty() {
+                                if let Some(path) = rfd::FileDialog::new()
+                                    .add_filter("gpl", &["gpl"])
+                                    .add_filter("ase", &["ase"])
+                                    .add_filter("json", &["json"])
+                                    .save_file()
+                                {
+                                    let path = if path.extension().is_none() {
+                                        path.with_extension("gpl")
+                                    } else {
+                                        path
+                                    };
+                                    if let Err(e) = swatches::save(&self.palette, &path) {
+                                        self.status_message =
+                                            format!("Could Not Save Palette: {}", e);
+                                    }
+                                }
+                            }
+                            ui.close_menu();
+                        }
+                        if ui.button("Export animation").clicked() {
+                            self.export_animation(ui.ctx());
+                            ui.close_menu();
+                        }
                         if ui.button("Reset").clicked() {
                             self.reset();
                             ui.close_menu();
@@ -191,6 +391,18 @@ impl eframe::App for App {
                             self.combine = Combine::Mix;
                             ui.close_menu();
                         }
+                        if ui.button("Reinhard").clicked() {
+                            self.combine = Combine::Reinhard;
+                            ui.close_menu();
+                        }
+                        if ui.button("Histogram").clicked() {
+                            self.combine = Combine::Histogram;
+                            ui.close_menu();
+                        }
+                        if ui.button("Palette").clicked() {
+                            self.combine = Combine::Palette;
+                            ui.close_menu();
+                        }
                         if ui.button("Warp").clicked() {
                             self.combine = Combine::Warp;
                             ui.close_menu();
@@ -203,6 +415,38 @@ impl eframe::App for App {
                             self.combine = Combine::Sort;
                             ui.close_menu();
                         }
+                        if ui.button("Cross Sort").clicked() {
+                            self.combine = Combine::CrossSort;
+                            ui.close_menu();
+                        }
+                        if ui.button("Transfer").clicked() {
+                            self.combine = Combine::Transfer;
+                            ui.close_menu();
+                        }
+                        if ui.button("Melt").clicked() {
+                            self.combine = Combine::Melt;
+                            ui.close_menu();
+                        }
+                        if ui.button("Remap").clicked() {
+                            self.combine = Combine::Remap;
+                            ui.close_menu();
+                        }
+                        if ui.button("Key").clicked() {
+                            self.combine = Combine::Key;
+                            ui.close_menu();
+                        }
+                        if ui.button("Matte").clicked() {
+                            self.combine = Combine::Matte;
+                            ui.close_menu();
+                        }
+                        if ui.button("Hybrid").clicked() {
+                            self.combine = Combine::Hybrid;
+                            ui.close_menu();
+                        }
+                        if ui.button("Spectrum").clicked() {
+                            self.combine = Combine::Spectrum;
+                            ui.close_menu();
+                        }
                     });
                 }
             });
@@ -219,11 +463,12 @@ impl eframe::App for App {
                 });
                 ui.separator();
                 ui.add_space(SPACE);
-                let button_label = if self.combine == Combine::Sort {
-                    "  Image"
-                } else {
-                    "  Image 1"
-                };
+                let button_label =
+                    if matches!(self.combine, Combine::Sort | Combine::Melt | Combine::Remap) {
+                        "  Image"
+                    } else {
+                        "  Image 1"
+                    };
                 ui.horizontal(|ui| {
                     if ui
                         .add(
@@ -304,13 +549,24 @@ impl eframe::App for App {
                                 .trailing_fill(true),
                         );
                         ui.end_row();
+
+                        if self.combine == Combine::Blend || self.combine == Combine::Mix {
+                            opacity_map_ui(
+                                ui,
+                                "1",
+                                &mut self.opacity_map_1,
+                                &mut self.opacity_img_1,
+                                &mut self.status_message,
+                                shift_held,
+                            );
+                        }
                     });
 
                 ui.add_space(SPACE);
                 ui.separator();
                 ui.add_space(SPACE);
 
-                if self.combine != Combine::Sort {
+                if !matches!(self.combine, Combine::Sort | Combine::Melt | Combine::Remap) {
                     ui.horizontal(|ui| {
                         if ui
                             .add(
@@ -407,6 +663,17 @@ impl eframe::App for App {
                                     .trailing_fill(true),
                             );
                             ui.end_row();
+
+                            if self.combine == Combine::Blend || self.combine == Combine::Mix {
+                                opacity_map_ui(
+                                    ui,
+                                    "2",
+                                    &mut self.opacity_map_2,
+                                    &mut self.opacity_img_2,
+                                    &mut self.status_message,
+                                    shift_held,
+                                );
+                            }
                         });
 
                     ui.add_space(SPACE);
@@ -482,31 +749,56 @@ impl eframe::App for App {
                     .spacing((20.0, 10.0))
                     .min_col_width(100.0)
                     .show(ui, |ui| {
-                        if self.combine == Combine::Unsort || self.combine == Combine::Sort {
-                            ui.label("Sort By");
-                            ComboBox::from_id_salt("sort by")
-                                .width(150.0)
-                                .selected_text(format!("{:?}", self.sort_by))
-                                .show_ui(ui, |ui| {
-                                    ui.set_min_width(60.0);
-                                    ui.selectable_value(&mut self.sort_by, SortBy::Row, "Row");
-                                    ui.selectable_value(
-                                        &mut self.sort_by,
-                                        SortBy::Column,
-                                        "Column",
-                                    );
-                                    ui.selectable_value(
-                                        &mut self.sort_by,
-                                        SortBy::RowCol,
-                                        "Row Column",
+                        if matches!(
+                            self.combine,
+                            Combine::Unsort
+                                | Combine::Sort
+                                | Combine::CrossSort
+                                | Combine::Transfer
+                                | Combine::Melt
+                        ) {
+                            if self.combine == Combine::Transfer {
+                                ui.label("Whole Image").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "Rank the pixels of the",
                                     );
-                                    ui.selectable_value(
-                                        &mut self.sort_by,
-                                        SortBy::ColRow,
-                                        "Column Row",
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "whole image instead of",
                                     );
+                                    ui.colored_label(egui::Color32::ORANGE, "each row or column.");
                                 });
-                            ui.end_row();
+                                ui.add(egui::Checkbox::new(&mut self.transfer_whole, ""));
+                                ui.end_row();
+                            }
+
+                            if self.combine != Combine::Melt {
+                                ui.label("Sort By");
+                                ComboBox::from_id_salt("sort by")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.sort_by))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(&mut self.sort_by, SortBy::Row, "Row");
+                                        ui.selectable_value(
+                                            &mut self.sort_by,
+                                            SortBy::Column,
+                                            "Column",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.sort_by,
+                                            SortBy::RowCol,
+                                            "Row Column",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.sort_by,
+                                            SortBy::ColRow,
+                                            "Column Row",
+                                        );
+                                    });
+                                ui.end_row();
+                            }
 
                             ui.label("Sort Key");
                             ComboBox::from_id_salt("sort key")
@@ -558,103 +850,1012 @@ impl eframe::App for App {
                                         SortKey::Chroma,
                                         "Chroma",
                                     );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::OklabL,
+                                        "Oklab L",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::OklchChroma,
+                                        "Oklch Chroma",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::OklchHue,
+                                        "Oklch Hue",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::LabL,
+                                        "Lab L*",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::LabA,
+                                        "Lab a*",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::LabB,
+                                        "Lab b*",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::HsvValue,
+                                        "HSV Value",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::Luminance,
+                                        "Luminance",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::Distance,
+                                        "Distance",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.sort_key,
+                                        SortKey::Custom,
+                                        "Custom",
+                                    );
+                                });
+                            ui.end_row();
+
+                            if self.sort_key == SortKey::Distance {
+                                ui.label("Reference").on_hover_ui(|ui| {
+                                    ui.colored_label(egui::Color32::ORANGE, "Sort by distance in");
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "Oklab from this color.",
+                                    );
+                                });
+                                ui.color_edit_button_srgb(&mut self.sort_reference);
+                                ui.end_row();
+                            }
+
+                            if self.sort_key == SortKey::Custom {
+                                let mut remove = None;
+                                for (i, term) in self.custom_terms.iter_mut().enumerate() {
+                                    ui.label(format!("Term {}", i + 1)).on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Feature, weight and",
+                                        );
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "quantization levels (0 is off).",
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ComboBox::from_id_salt(("sort term", i))
+                                            .width(80.0)
+                                            .selected_text(format!("{:?}", term.feature))
+                                            .show_ui(ui, |ui| {
+                                                ui.set_min_width(60.0);
+                                                for feature in [
+                                                    SortFeature::Red,
+                                                    SortFeature::Green,
+                                                    SortFeature::Blue,
+                                                    SortFeature::Alpha,
+                                                    SortFeature::Luma,
+                                                    SortFeature::Hue,
+                                                    SortFeature::Saturation,
+                                                    SortFeature::Lightness,
+                                                    SortFeature::OklchL,
+                                                    SortFeature::OklchChroma,
+                                                    SortFeature::OklchHue,
+                                                    SortFeature::X,
+                                                    SortFeature::Y,
+                                                    SortFeature::Noise,
+                                                ] {
+                                                    ui.selectable_value(
+                                                        &mut term.feature,
+                                                        feature,
+                                                        format!("{:?}", feature),
+                                                    );
+                                                }
+                                            });
+                                        ui.add(
+                                            egui::DragValue::new(&mut term.weight)
+                                                .speed(0.01)
+                                                .max_decimals(2),
+                                        );
+                                        ui.add(
+                                            egui::DragValue::new(&mut term.levels).range(0..=256),
+                                        );
+                                        if ui.small_button("✖").clicked() {
+                                            remove = Some(i);
+                                        }
+                                    });
+                                    ui.end_row();
+                                }
+                                if let Some(i) = remove {
+                                    self.custom_terms.remove(i);
+                                }
+                                ui.label("");
+                                if ui.small_button("Add Term").clicked() {
+                                    self.custom_terms.push(SortTerm {
+                                        feature: SortFeature::Luma,
+                                        weight: 1.0,
+                                        levels: 0,
+                                    });
+                                }
+                                ui.end_row();
+                            }
+
+                            if self.combine == Combine::Melt {
+                                ui.label("Direction");
+                                ComboBox::from_id_salt("melt direction")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.melt_direction))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        for dir in [
+                                            MeltDirection::Down,
+                                            MeltDirection::Up,
+                                            MeltDirection::Left,
+                                            MeltDirection::Right,
+                                        ] {
+                                            ui.selectable_value(
+                                                &mut self.melt_direction,
+                                                dir,
+                                                format!("{:?}", dir),
+                                            );
+                                        }
+                                    });
+                                ui.end_row();
+
+                                ui.label("Order");
+                                ComboBox::from_id_salt("melt order")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.melt_order))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.melt_order,
+                                            SortOrder::Ascending,
+                                            "Ascending",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.melt_order,
+                                            SortOrder::Descending,
+                                            "Descending",
+                                        );
+                                    });
+                                ui.end_row();
+
+                                ui.label("Threshold").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "A run starts at each pixel",
+                                    );
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "whose key is above this, as",
+                                    );
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "a fraction of the key range.",
+                                    );
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.melt_threshold, 0.0..=1.0)
+                                            .step_by(if shift_held { 0.05 } else { 0.01 })
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.melt_threshold = App::default().melt_threshold;
+                                    }
+                                });
+                                ui.end_row();
+
+                                ui.label("Run Length").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "The longest run in pixels,",
+                                    );
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "each is scaled by noise.",
+                                    );
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.melt_length, 1.0..=2000.0)
+                                            .step_by(if shift_held { 50.0 } else { 1.0 })
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.melt_length = App::default().melt_length;
+                                    }
+                                });
+                                ui.end_row();
+
+                                ui.label("Run Noise");
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.melt_scale, 0.0..=20.0)
+                                            .step_by(if shift_held { 1.0 } else { 0.1 })
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.melt_scale = App::default().melt_scale;
+                                    }
+                                });
+                                ui.end_row();
+                            } else {
+                                ui.label("Row Order");
+                                ComboBox::from_id_salt("row sort order")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.row_sort_order))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.row_sort_order,
+                                            SortOrder::Ascending,
+                                            "Ascending",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.row_sort_order,
+                                            SortOrder::Descending,
+                                            "Descending",
+                                        );
+                                    });
+                                ui.end_row();
+
+                                ui.label("Column Order");
+                                ComboBox::from_id_salt("col sort order")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.col_sort_order))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.col_sort_order,
+                                            SortOrder::Ascending,
+                                            "Ascending",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.col_sort_order,
+                                            SortOrder::Descending,
+                                            "Descending",
+                                        );
+                                    });
+                                ui.end_row();
+
+                                ui.label("Cells").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "Sort the pixels within",
+                                    );
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "each cell independently.",
+                                    );
+                                });
+                                ComboBox::from_id_salt("cell layout")
+                                    .width(150.0)
+                                    .selected_text(format!("{:?}", self.cell_layout))
+                                    .show_ui(ui, |ui| {
+                                        ui.set_min_width(60.0);
+                                        ui.selectable_value(
+                                            &mut self.cell_layout,
+                                            CellLayout::Off,
+                                            "Off",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.cell_layout,
+                                            CellLayout::Square,
+                                            "Square",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.cell_layout,
+                                            CellLayout::Hex,
+                                            "Hex",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.cell_layout,
+                                            CellLayout::Voronoi,
+                                            "Voronoi",
+                                        );
+                                    });
+                                ui.end_row();
+
+                                if self.cell_layout != CellLayout::Off {
+                                    ui.label("Cell Size");
+                                    ui.horizontal(|ui| {
+                                        ui.add(
+                                            egui::Slider::new(&mut self.cell_size, 4..=1024)
+                                                .step_by(if shift_held { 16.0 } else { 1.0 })
+                                                .trailing_fill(true),
+                                        );
+                                        if ui.small_button("↺").clicked() {
+                                            self.cell_size = App::default().cell_size;
+                                        }
+                                    });
+                                    ui.end_row();
+
+                                    ui.label("Random Cells").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Give each cell a random",
+                                        );
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "direction and order.",
+                                        );
+                                    });
+                                    ui.add(egui::Checkbox::new(&mut self.cell_random, ""));
+                                    ui.end_row();
+                                }
+                            }
+
+                            ui.label("Strength").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "How far each pixel moves");
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "towards its sorted position.",
+                                );
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.sort_strength, 0.0..=1.0)
+                                        .step_by(if shift_held { 0.1 } else { 0.01 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.sort_strength = App::default().sort_strength;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Strength Noise").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Modulate the strength with",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "noise so some regions sort",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "fully and others stay intact.",
+                                );
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.strength_noise, 0.0..=1.0)
+                                        .step_by(if shift_held { 0.1 } else { 0.01 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.strength_noise = App::default().strength_noise;
+                                }
+                            });
+                            ui.end_row();
+
+                            if self.strength_noise > 0.0 {
+                                ui.label("Noise Scale");
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.strength_scale, 0.0..=20.0)
+                                            .step_by(if shift_held { 1.0 } else { 0.1 })
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.strength_scale = App::default().strength_scale;
+                                    }
+                                });
+                                ui.end_row();
+                            }
+
+                            ui.label("Extra Images").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Apply the same pixel map");
+                                ui.colored_label(egui::Color32::ORANGE, "to these images, saved");
+                                ui.colored_label(egui::Color32::ORANGE, "next to the output.");
+                            });
+                            if ui.small_button("Add").clicked() {
+                                if let Some(paths) = rfd::FileDialog::new()
+                                    .add_filter("image", &["png", "jpg", "jpeg"])
+                                    .pick_files()
+                                {
+                                    for path in paths {
+                                        match image::open(&path) {
+                                            Ok(img) => {
+                                                self.extra_imgs.push(img.to_rgba8());
+                                                self.extra_paths.push(path.display().to_string());
+                                            }
+                                            Err(e) => {
+                                                self.status_message = format!(
+                                                    "Could Not Load Extra Image {}: {}",
+                                                    path.display(),
+                                                    e
+                                                );
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            ui.end_row();
+
+                            let mut remove = None;
+                            for (i, path) in self.extra_paths.iter().enumerate() {
+                                ui.label("");
+                                ui.horizontal(|ui| {
+                                    let path = PathBuf::from(path);
+                                    if let Some(file_name) = path.file_name() {
+                                        ui.label(file_name.to_string_lossy());
+                                    }
+                                    if ui.small_button("✖").clicked() {
+                                        remove = Some(i);
+                                    }
                                 });
+                                ui.end_row();
+                            }
+                            if let Some(i) = remove {
+                                self.extra_paths.remove(i);
+                                self.extra_imgs.remove(i);
+                            }
+
+                            ui.label("Sort Mask").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Also apply the pixel map");
+                                ui.colored_label(egui::Color32::ORANGE, "to the Divide/Mix mask.");
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.sort_mask, ""));
+                            ui.end_row();
+
+                            ui.label("Frames").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "The number of frames in");
+                                ui.colored_label(egui::Color32::ORANGE, "an exported animation.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.anim_frames, 2..=240)
+                                        .step_by(if shift_held { 10.0 } else { 1.0 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.anim_frames = App::default().anim_frames;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Frame Delay").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Milliseconds per frame.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.anim_delay, 10..=1000)
+                                        .step_by(if shift_held { 10.0 } else { 1.0 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.anim_delay = App::default().anim_delay;
+                                }
+                            });
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Warp {
+                            ui.label("Angle Scale");
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.angle_scale, 0.0..=20.0)
+                                        .trailing_fill(true)
+                                        .trailing_fill(true)
+                                        .step_by(if shift_held { 1.0 } else { 0.1 }),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.angle_scale = App::default().angle_scale;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Angle Factor");
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.angle_factor, 0.0..=250.0)
+                                        .step_by(if shift_held { 10.0 } else { 1.0 })
+                                        .trailing_fill(true)
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.angle_factor = App::default().angle_factor;
+                                }
+                            });
                             ui.end_row();
 
-                            ui.label("Row Order");
-                            ComboBox::from_id_salt("row sort order")
+                            ui.label("Radius Scale");
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.radius_scale, 0.0..=20.0)
+                                        .step_by(if shift_held { 1.0 } else { 0.05 })
+                                        .trailing_fill(true)
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.radius_scale = App::default().radius_scale;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Radius Factor");
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.radius_factor, 0.0..=5000.0)
+                                        .step_by(if shift_held { 250.0 } else { 50.0 })
+                                        .trailing_fill(true)
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.radius_factor = App::default().radius_factor;
+                                }
+                            });
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Key {
+                            ui.label("Key").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Key out image 1 by its");
+                                ui.colored_label(egui::Color32::ORANGE, "luma or by closeness to");
+                                ui.colored_label(egui::Color32::ORANGE, "a key color, showing");
+                                ui.colored_label(egui::Color32::ORANGE, "image 2 through.");
+                            });
+                            ComboBox::from_id_salt("key kind")
                                 .width(150.0)
-                                .selected_text(format!("{:?}", self.row_sort_order))
+                                .selected_text(format!("{:?}", self.key_kind))
                                 .show_ui(ui, |ui| {
                                     ui.set_min_width(60.0);
+                                    ui.selectable_value(&mut self.key_kind, KeyKind::Luma, "Luma");
                                     ui.selectable_value(
-                                        &mut self.row_sort_order,
-                                        SortOrder::Ascending,
-                                        "Ascending",
-                                    );
-                                    ui.selectable_value(
-                                        &mut self.row_sort_order,
-                                        SortOrder::Descending,
-                                        "Descending",
+                                        &mut self.key_kind,
+                                        KeyKind::Chroma,
+                                        "Chroma",
                                     );
                                 });
                             ui.end_row();
 
-                            ui.label("Column Order");
-                            ComboBox::from_id_salt("col sort order")
+                            match self.key_kind {
+                                KeyKind::Luma => {
+                                    ui.label("Threshold").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Pixels brighter than this",
+                                        );
+                                        ui.colored_label(egui::Color32::ORANGE, "are keyed out.");
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.add(
+                                            egui::Slider::new(&mut self.key_threshold, 0.0..=255.0)
+                                                .step_by(if shift_held { 10.0 } else { 1.0 })
+                                                .trailing_fill(true),
+                                        );
+                                        if ui.small_button("↺").clicked() {
+                                            self.key_threshold = App::default().key_threshold;
+                                        }
+                                    });
+                                    ui.end_row();
+
+                                    ui.label("Key Below").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Key out pixels darker than",
+                                        );
+                                        ui.colored_label(egui::Color32::ORANGE, "the threshold.");
+                                    });
+                                    ui.add(egui::Checkbox::new(&mut self.key_below, ""));
+                                    ui.end_row();
+                                }
+                                KeyKind::Chroma => {
+                                    ui.label("Key Color");
+                                    ui.color_edit_button_srgb(&mut self.key_color);
+                                    ui.end_row();
+
+                                    ui.label("Tolerance").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Pixels this close to the key",
+                                        );
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "color in Oklab chroma are",
+                                        );
+                                        ui.colored_label(egui::Color32::ORANGE, "keyed out.");
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.add(
+                                            egui::Slider::new(&mut self.key_tolerance, 0.0..=150.0)
+                                                .step_by(if shift_held { 5.0 } else { 1.0 })
+                                                .trailing_fill(true),
+                                        );
+                                        if ui.small_button("↺").clicked() {
+                                            self.key_tolerance = App::default().key_tolerance;
+                                        }
+                                    });
+                                    ui.end_row();
+
+                                    ui.label("Spill").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "Remove the key color cast",
+                                        );
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "from the pixels kept.",
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.add(
+                                            egui::Slider::new(&mut self.key_spill, 0.0..=1.0)
+                                                .step_by(if shift_held { 0.1 } else { 0.01 })
+                                                .trailing_fill(true),
+                                        );
+                                        if ui.small_button("↺").clicked() {
+                                            self.key_spill = App::default().key_spill;
+                                        }
+                                    });
+                                    ui.end_row();
+                                }
+                            }
+
+                            ui.label("Softness").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Blend the two images");
+                                ui.colored_label(egui::Color32::ORANGE, "over this range at the");
+                                ui.colored_label(egui::Color32::ORANGE, "edge of the key.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.key_softness, 0.0..=100.0)
+                                        .step_by(if shift_held { 5.0 } else { 1.0 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.key_softness = App::default().key_softness;
+                                }
+                            });
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Matte {
+                            ui.label("Threshold").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Pixels that differ by more",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "than this in Oklab come");
+                                ui.colored_label(egui::Color32::ORANGE, "from image 1.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.matte_threshold, 0.0..=150.0)
+                                        .step_by(if shift_held { 5.0 } else { 1.0 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.matte_threshold = App::default().matte_threshold;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Softness").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Ramp the matte from black",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "to white over this range");
+                                ui.colored_label(egui::Color32::ORANGE, "above the threshold.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.matte_softness, 0.0..=100.0)
+                                        .step_by(if shift_held { 5.0 } else { 1.0 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.matte_softness = App::default().matte_softness;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Matte Blur").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Blur the matte to feather",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "its edges.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.matte_blur, 0.0..=50.0)
+                                        .step_by(if shift_held { 5.0 } else { 0.5 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.matte_blur = App::default().matte_blur;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Swap").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Take the differences from",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "image 2 and the rest from",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "image 1.");
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.matte_swap, ""));
+                            ui.end_row();
+
+                            ui.label("Show Matte");
+                            ui.add(egui::Checkbox::new(&mut self.matte_show, ""));
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Hybrid {
+                            ui.label("Cutoff Radius").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "The blur that splits the");
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "forms of image 1 from the",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "detail of image 2.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.hybrid_cutoff, 0.0..=100.0)
+                                        .step_by(if shift_held { 5.0 } else { 0.5 })
+                                        .clamping(SliderClamping::Never)
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.hybrid_cutoff = App::default().hybrid_cutoff;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Detail").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Scale the fine detail");
+                                ui.colored_label(egui::Color32::ORANGE, "taken from image 2.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.hybrid_detail, 0.0..=4.0)
+                                        .step_by(if shift_held { 0.5 } else { 0.05 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.hybrid_detail = App::default().hybrid_detail;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Swap").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Take the forms from image 2",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "and the detail from image 1.",
+                                );
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.hybrid_swap, ""));
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Spectrum {
+                            ui.label("Mix").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "How much of the magnitude",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "spectrum of image 2 is put",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "with the phase of image 1.",
+                                );
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.spectrum_mix, 0.0..=1.0)
+                                        .step_by(if shift_held { 0.1 } else { 0.01 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.spectrum_mix = App::default().spectrum_mix;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Luminance").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Swap the spectrum of the");
+                                ui.colored_label(egui::Color32::ORANGE, "lightness only and keep");
+                                ui.colored_label(egui::Color32::ORANGE, "the colors of image 1.");
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.spectrum_luma, ""));
+                            ui.end_row();
+
+                            ui.label("Swap").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Take the phase from image 2",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "and the magnitude from");
+                                ui.colored_label(egui::Color32::ORANGE, "image 1.");
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.spectrum_swap, ""));
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Reinhard {
+                            ui.label("Strength").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "How far image 1 moves to");
+                                ui.colored_label(egui::Color32::ORANGE, "the color statistics of");
+                                ui.colored_label(egui::Color32::ORANGE, "image 2.");
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut self.reinhard_strength, 0.0..=1.0)
+                                        .step_by(if shift_held { 0.1 } else { 0.01 })
+                                        .trailing_fill(true),
+                                );
+                                if ui.small_button("↺").clicked() {
+                                    self.reinhard_strength = App::default().reinhard_strength;
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Luminance").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Match the lightness only");
+                                ui.colored_label(egui::Color32::ORANGE, "and keep the colors of");
+                                ui.colored_label(egui::Color32::ORANGE, "image 1.");
+                            });
+                            ui.add(egui::Checkbox::new(&mut self.reinhard_luma, ""));
+                            ui.end_row();
+                        }
+                        if self.combine == Combine::Histogram {
+                            ui.label("Match").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "Match each RGB channel,");
+                                ui.colored_label(egui::Color32::ORANGE, "the lightness only, or");
+                                ui.colored_label(egui::Color32::ORANGE, "each Oklch channel.");
+                            });
+                            ComboBox::from_id_salt("histogram space")
                                 .width(150.0)
-                                .selected_text(format!("{:?}", self.col_sort_order))
+                                .selected_text(format!("{:?}", self.histogram_space))
                                 .show_ui(ui, |ui| {
                                     ui.set_min_width(60.0);
                                     ui.selectable_value(
-                                        &mut self.col_sort_order,
-                                        SortOrder::Ascending,
-                                        "Ascending",
+                                        &mut self.histogram_space,
+                                        HistogramSpace::Rgb,
+                                        "Rgb",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.histogram_space,
+                                        HistogramSpace::Luma,
+                                        "Luma",
                                     );
                                     ui.selectable_value(
-                                        &mut self.col_sort_order,
-                                        SortOrder::Descending,
-                                        "Descending",
+                                        &mut self.histogram_space,
+                                        HistogramSpace::Oklch,
+                                        "Oklch",
                                     );
                                 });
                             ui.end_row();
-                        }
-                        if self.combine == Combine::Warp {
-                            ui.label("Angle Scale");
+
+                            ui.label("Strength").on_hover_ui(|ui| {
+                                ui.colored_label(egui::Color32::ORANGE, "How far image 1 moves to");
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "the histogram of image 2.",
+                                );
+                            });
                             ui.horizontal(|ui| {
                                 ui.add(
-                                    egui::Slider::new(&mut self.angle_scale, 0.0..=20.0)
-                                        .trailing_fill(true)
-                                        .trailing_fill(true)
-                                        .step_by(if shift_held { 1.0 } else { 0.1 }),
+                                    egui::Slider::new(&mut self.histogram_strength, 0.0..=1.0)
+                                        .step_by(if shift_held { 0.1 } else { 0.01 })
+                                        .trailing_fill(true),
                                 );
                                 if ui.small_button("↺").clicked() {
-                                    self.angle_scale = App::default().angle_scale;
+                                    self.histogram_strength = App::default().histogram_strength;
                                 }
                             });
                             ui.end_row();
-
-                            ui.label("Angle Factor");
+                        }
+                        if self.combine == Combine::Palette {
+                            ui.label("Colors").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "The number of colors taken",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "from image 2.");
+                            });
                             ui.horizontal(|ui| {
                                 ui.add(
-                                    egui::Slider::new(&mut self.angle_factor, 0.0..=250.0)
-                                        .step_by(if shift_held { 10.0 } else { 1.0 })
-                                        .trailing_fill(true)
+                                    egui::Slider::new(&mut self.palette_size, 2..=64)
+                                        .clamping(SliderClamping::Never)
                                         .trailing_fill(true),
                                 );
                                 if ui.small_button("↺").clicked() {
-                                    self.angle_factor = App::default().angle_factor;
+                                    self.palette_size = App::default().palette_size;
                                 }
                             });
                             ui.end_row();
 
-                            ui.label("Radius Scale");
-                            ui.horizontal(|ui| {
-                                ui.add(
-                                    egui::Slider::new(&mut self.radius_scale, 0.0..=20.0)
-                                        .step_by(if shift_held { 1.0 } else { 0.05 })
-                                        .trailing_fill(true)
-                                        .trailing_fill(true),
+                            ui.label("Method").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Find the colors by k-means",
                                 );
-                                if ui.small_button("↺").clicked() {
-                                    self.radius_scale = App::default().radius_scale;
-                                }
+                                ui.colored_label(egui::Color32::ORANGE, "or median cut in Oklab.");
                             });
+                            ComboBox::from_id_salt("palette method")
+                                .width(150.0)
+                                .selected_text(format!("{:?}", self.palette_method))
+                                .show_ui(ui, |ui| {
+                                    ui.set_min_width(60.0);
+                                    ui.selectable_value(
+                                        &mut self.palette_method,
+                                        PaletteMethod::KMeans,
+                                        "K-Means",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.palette_method,
+                                        PaletteMethod::MedianCut,
+                                        "Median Cut",
+                                    );
+                                });
                             ui.end_row();
 
-                            ui.label("Radius Factor");
-                            ui.horizontal(|ui| {
-                                ui.add(
-                                    egui::Slider::new(&mut self.radius_factor, 0.0..=5000.0)
-                                        .step_by(if shift_held { 250.0 } else { 50.0 })
-                                        .trailing_fill(true)
-                                        .trailing_fill(true),
+                            ui.label("Mapping").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Recolor each pixel with the",
                                 );
-                                if ui.small_button("↺").clicked() {
-                                    self.radius_factor = App::default().radius_factor;
-                                }
+                                ui.colored_label(egui::Color32::ORANGE, "nearest color, or by its");
+                                ui.colored_label(egui::Color32::ORANGE, "lightness rank.");
                             });
+                            ComboBox::from_id_salt("palette mapping")
+                                .width(150.0)
+                                .selected_text(format!("{:?}", self.palette_mapping))
+                                .show_ui(ui, |ui| {
+                                    ui.set_min_width(60.0);
+                                    ui.selectable_value(
+                                        &mut self.palette_mapping,
+                                        PaletteMapping::Nearest,
+                                        "Nearest",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.palette_mapping,
+                                        PaletteMapping::Rank,
+                                        "Rank",
+                                    );
+                                });
                             ui.end_row();
+
+                            if !self.palette.is_empty() {
+                                ui.label("Palette");
+                                ui.horizontal_wrapped(|ui| {
+                                    ui.spacing_mut().item_spacing = Vec2::splat(2.0);
+                                    for &[r, g, b] in &self.palette {
+                                        let (rect, response) = ui.allocate_exact_size(
+                                            Vec2::splat(18.0),
+                                            egui::Sense::hover(),
+                                        );
+                                        ui.painter().rect_filled(
+                                            rect,
+                                            2.0,
+                                            egui::Color32::from_rgb(r, g, b),
+                                        );
+                                        response
+                                            .on_hover_text(format!("#{:02x}{:02x}{:02x}", r, g, b));
+                                    }
+                                });
+                                ui.end_row();
+                            }
                         }
                         if self.combine == Combine::Divide || self.combine == Combine::Mix {
                             ui.label("Contamination");
@@ -695,6 +1896,45 @@ impl eframe::App for App {
                                 }
                             });
                             ui.end_row();
+
+                            if self.combine == Combine::Divide {
+                                ui.label("Multiband").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "Blend the images across the",
+                                    );
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "levels of a Laplacian pyramid",
+                                    );
+                                    ui.colored_label(egui::Color32::ORANGE, "to hide the seams.");
+                                });
+                                ui.add(egui::Checkbox::new(&mut self.multiband, ""));
+                                ui.end_row();
+
+                                if self.multiband {
+                                    ui.label("Levels").on_hover_ui(|ui| {
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "More levels blend color and",
+                                        );
+                                        ui.colored_label(
+                                            egui::Color32::ORANGE,
+                                            "exposure over wider areas.",
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.add(
+                                            egui::Slider::new(&mut self.multiband_levels, 1..=12)
+                                                .trailing_fill(true),
+                                        );
+                                        if ui.small_button("↺").clicked() {
+                                            self.multiband_levels = App::default().multiband_levels;
+                                        }
+                                    });
+                                    ui.end_row();
+                                }
+                            }
                         }
 
                         if self.combine == Combine::Blend || self.combine == Combine::Mix {
@@ -735,6 +1975,31 @@ impl eframe::App for App {
                                             BlendMode::Exclusion,
                                             "Exclusion",
                                         );
+                                        ui.selectable_value(
+                                            &mut self.mode,
+                                            BlendMode::LinearBurn,
+                                            "Linear Burn",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.mode,
+                                            BlendMode::LinearDodge,
+                                            "Linear Dodge",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.mode,
+                                            BlendMode::HardMix,
+                                            "Hard Mix",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.mode,
+                                            BlendMode::DarkerColor,
+                                            "Darker Color",
+                                        );
+                                        ui.selectable_value(
+                                            &mut self.mode,
+                                            BlendMode::LighterColor,
+                                            "Lighter Color",
+                                        );
                                     }
                                     ui.selectable_value(
                                         &mut self.mode,
@@ -753,6 +2018,43 @@ impl eframe::App for App {
                                         BlendMode::SoftLight,
                                         "Soft Light",
                                     );
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::LinearLight,
+                                        "Linear Light",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::VividLight,
+                                        "Vivid Light",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::PinLight,
+                                        "Pin Light",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::Subtract,
+                                        "Subtract",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::Divide,
+                                        "Divide",
+                                    );
+                                    ui.selectable_value(&mut self.mode, BlendMode::Hue, "Hue");
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::Saturation,
+                                        "Saturation",
+                                    );
+                                    ui.selectable_value(&mut self.mode, BlendMode::Color, "Color");
+                                    ui.selectable_value(
+                                        &mut self.mode,
+                                        BlendMode::Luminosity,
+                                        "Luminosity",
+                                    );
                                     ui.selectable_value(
                                         &mut self.mode,
                                         BlendMode::Normal,
@@ -760,6 +2062,214 @@ impl eframe::App for App {
                                     );
                                 });
                             ui.end_row();
+
+                            ui.label("Blend Space").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Linear light, gamma encoded",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "sRGB like most editors, or",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "perceptual Oklab.");
+                            });
+                            ComboBox::from_id_salt("blend space")
+                                .width(150.0)
+                                .selected_text(format!("{:?}", self.blend_space))
+                                .show_ui(ui, |ui| {
+                                    ui.set_min_width(60.0);
+                                    ui.selectable_value(
+                                        &mut self.blend_space,
+                                        BlendSpace::Linear,
+                                        "Linear",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.blend_space,
+                                        BlendSpace::Srgb,
+                                        "sRGB",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.blend_space,
+                                        BlendSpace::Oklab,
+                                        "Oklab",
+                                    );
+                                });
+                            ui.end_row();
+                        }
+                    });
+
+                ui.add_space(SPACE);
+                ui.separator();
+                ui.add_space(SPACE);
+
+                Grid::new("quantize grid")
+                    .spacing((20.0, 10.0))
+                    .min_col_width(100.0)
+                    .show(ui, |ui| {
+                        ui.label("Quantize").on_hover_ui(|ui| {
+                            ui.colored_label(
+                                egui::Color32::ORANGE,
+                                "Reduce the combined image to a",
+                            );
+                            ui.colored_label(
+                                egui::Color32::ORANGE,
+                                "few colors before the overlay.",
+                            );
+                        });
+                        ui.add(egui::Checkbox::new(&mut self.quantize, ""));
+                        ui.end_row();
+
+                        if self.quantize {
+                            ui.label("Palette").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Levels per channel, colors taken",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "from image 2 or a fixed palette.",
+                                );
+                            });
+                            ComboBox::from_id_salt("quantize palette")
+                                .width(150.0)
+                                .selected_text(format!("{:?}", self.quantize_palette))
+                                .show_ui(ui, |ui| {
+                                    ui.set_min_width(60.0);
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::Levels,
+                                        "Levels",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::Image2,
+                                        "Image 2",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::Mono,
+                                        "Mono",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::Cmyk,
+                                        "CMYK",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::Riso,
+                                        "Riso",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.quantize_palette,
+                                        QuantizePalette::GameBoy,
+                                        "Game Boy",
+                                    );
+                                });
+                            ui.end_row();
+
+                            if self.quantize_palette == QuantizePalette::Levels {
+                                ui.label("Levels").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "The number of levels in each",
+                                    );
+                                    ui.colored_label(egui::Color32::ORANGE, "RGB channel.");
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.quantize_levels, 2..=16)
+                                            .clamping(SliderClamping::Never)
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.quantize_levels = App::default().quantize_levels;
+                                    }
+                                });
+                                ui.end_row();
+                            }
+
+                            if self.quantize_palette == QuantizePalette::Image2 {
+                                ui.label("Colors").on_hover_ui(|ui| {
+                                    ui.colored_label(
+                                        egui::Color32::ORANGE,
+                                        "The number of colors taken",
+                                    );
+                                    ui.colored_label(egui::Color32::ORANGE, "from image 2.");
+                                });
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.quantize_colors, 2..=64)
+                                            .clamping(SliderClamping::Never)
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.quantize_colors = App::default().quantize_colors;
+                                    }
+                                });
+                                ui.end_row();
+                            }
+
+                            ui.label("Dither").on_hover_ui(|ui| {
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "Spread the error by diffusion,",
+                                );
+                                ui.colored_label(
+                                    egui::Color32::ORANGE,
+                                    "or threshold by a Bayer or",
+                                );
+                                ui.colored_label(egui::Color32::ORANGE, "blue noise pattern.");
+                            });
+                            ComboBox::from_id_salt("dither kind")
+                                .width(150.0)
+                                .selected_text(format!("{:?}", self.dither_kind))
+                                .show_ui(ui, |ui| {
+                                    ui.set_min_width(60.0);
+                                    ui.selectable_value(
+                                        &mut self.dither_kind,
+                                        DitherKind::None,
+                                        "None",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.dither_kind,
+                                        DitherKind::FloydSteinberg,
+                                        "Floyd-Steinberg",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.dither_kind,
+                                        DitherKind::Atkinson,
+                                        "Atkinson",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.dither_kind,
+                                        DitherKind::Bayer,
+                                        "Bayer",
+                                    );
+                                    ui.selectable_value(
+                                        &mut self.dither_kind,
+                                        DitherKind::BlueNoise,
+                                        "Blue Noise",
+                                    );
+                                });
+                            ui.end_row();
+
+                            if self.dither_kind != DitherKind::None {
+                                ui.label("Dither Amount");
+                                ui.horizontal(|ui| {
+                                    ui.add(
+                                        egui::Slider::new(&mut self.dither_amount, 0.0..=1.0)
+                                            .step_by(if shift_held { 0.05 } else { 0.01 })
+                                            .clamping(SliderClamping::Never)
+                                            .trailing_fill(true),
+                                    );
+                                    if ui.small_button("↺").clicked() {
+                                        self.dither_amount = App::default().dither_amount;
+                                    }
+                                });
+                                ui.end_row();
+                            }
                         }
                     });
 
@@ -908,7 +2418,7 @@ impl eframe::App for App {
                             .add(Button::new(button_text).min_size(Vec2::new(125.0, 25.0)))
                             .clicked()
                         {
-                            if !self.drawing_in_progress {
+                            if !self.drawing_in_progress && self.export_receiver.is_none() {
                                 let (tx, rx) = std::sync::mpsc::channel();
                                 let (status_tx, status_rx) = std::sync::mpsc::channel();
                                 let mut app_clone = self.clone();
@@ -922,15 +2432,15 @@ impl eframe::App for App {
                                 self.status_message_arc = Some(status_message);
 
                                 thread::spawn(move || {
-                                    let img = draw(&app_clone, status_tx);
+                                    let render = draw(&app_clone, status_tx);
                                     let size =
                                         dims(app_clone.width as f32, app_clone.height as f32);
                                     let texture = ctx.load_texture(
                                         "draw",
-                                        to_color_image(&img, size.0 as u32, size.1 as u32),
+                                        to_color_image(&render.img, size.0 as u32, size.1 as u32),
                                         Default::default(),
                                     );
-                                    let _ = tx.send((texture, img));
+                                    let _ = tx.send((texture, render));
                                 });
 
                                 // Start a thread to receive status updates
@@ -959,9 +2469,16 @@ impl eframe::App for App {
 
                 // Check for completed drawing and update status
                 if let Some(receiver) = &self.draw_receiver {
-                    if let Ok((texture, img)) = receiver.try_recv() {
+                    if let Ok((texture, render)) = receiver.try_recv() {
                         self.texture = Some(texture);
-                        self.img = img;
+                        self.img = render.img;
+                        if let Some(px_map) = render.px_map {
+                            self.pixel_map = Some(Arc::new(px_map));
+                        }
+                        self.map_source = render.source;
+                        self.extra_outputs = render.extras;
+                        self.palette = render.palette;
+                        self.quantizer = render.quantizer;
                         self.drawing_in_progress = false;
                         self.draw_receiver = None;
                         self.status_message = String::new();
@@ -978,6 +2495,26 @@ impl eframe::App for App {
                     }
                 }
 
+                // Check for a finished animation export and update status
+                if let Some(receiver) = &self.export_receiver {
+                    if let Ok(result) = receiver.try_recv() {
+                        self.status_message = match result {
+                            Ok(()) => "Animation Saved".to_string(),
+                            Err(e) => format!("Could Not Save Animation: {}", e),
+                        };
+                        self.export_receiver = None;
+                        self.status_message_arc = None;
+                        ui.ctx().request_repaint();
+                    } else if let Some(status_arc) = &self.status_message_arc {
+                        if let Ok(status) = status_arc.lock() {
+                            if !status.is_empty() {
+                                self.status_message = status.clone();
+                                ui.ctx().request_repaint();
+                            }
+                        }
+                    }
+                }
+
                 ui.add_space(SPACE);
             });
 
@@ -1091,7 +2628,7 @@ impl eframe::App for App {
                     ui.add_space(spacing_between);
 
                     // Second thumbnail with centered label
-                    if self.combine != Combine::Sort {
+                    if !matches!(self.combine, Combine::Sort | Combine::Melt | Combine::Remap) {
                         ui.allocate_ui(
                             egui::vec2(thumbnail_width, thumbnail_height + SPACE + 20.0),
                             |ui| {
@@ -1144,3 +2681,107 @@ impl eframe::App for App {
         });
     }
 }
+
+// The rows of an image grid that set the image's opacity map.
+fn opacity_map_ui(
+    ui: &mut Ui,
+    id: &str,
+    map: &mut OpacityMap,
+    img: &mut Option<GrayImage>,
+    status_message: &mut String,
+    shift_held: bool,
+) {
+    ui.label("Opacity Map").on_hover_ui(|ui| {
+        ui.colored_label(egui::Color32::ORANGE, "Vary the opacity over the");
+        ui.colored_label(egui::Color32::ORANGE, "image with a gradient, noise");
+        ui.colored_label(egui::Color32::ORANGE, "or a grayscale image.");
+    });
+    ComboBox::from_id_salt(format!("opacity map {}", id))
+        .width(150.0)
+        .selected_text(format!("{:?}", map.kind))
+        .show_ui(ui, |ui| {
+            ui.set_min_width(60.0);
+            for kind in [
+                OpacityKind::Constant,
+                OpacityKind::Linear,
+                OpacityKind::Radial,
+                OpacityKind::Noise,
+                OpacityKind::Image,
+            ] {
+                ui.selectable_value(&mut map.kind, kind, format!("{:?}", kind));
+            }
+        });
+    ui.end_row();
+
+    match map.kind {
+        OpacityKind::Constant | OpacityKind::Radial => {}
+        OpacityKind::Linear => {
+            ui.label("Angle").on_hover_ui(|ui| {
+                ui.colored_label(egui::Color32::ORANGE, "The direction of the gradient");
+                ui.colored_label(egui::Color32::ORANGE, "in degrees, opaque at its end.");
+            });
+            ui.add(
+                egui::Slider::new(&mut map.angle, 0.0..=360.0)
+                    .step_by(if shift_held { 15.0 } else { 5.0 })
+                    .trailing_fill(true),
+            );
+            ui.end_row();
+        }
+        OpacityKind::Noise => {
+            ui.label("Noise Scale").on_hover_ui(|ui| {
+                ui.colored_label(egui::Color32::ORANGE, "The scale of the noise,");
+                ui.colored_label(egui::Color32::ORANGE, "higher is finer.");
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut map.scale, 0.5..=20.0)
+                        .step_by(if shift_held { 1.0 } else { 0.5 })
+                        .trailing_fill(true),
+                );
+                if ui.small_button("↺").clicked() {
+                    map.scale = OpacityMap::default().scale;
+                }
+            });
+            ui.end_row();
+        }
+        OpacityKind::Image => {
+            ui.label("Map Image").on_hover_ui(|ui| {
+                ui.colored_label(egui::Color32::ORANGE, "A grayscale image stretched");
+                ui.colored_label(egui::Color32::ORANGE, "over the output, white is");
+                ui.colored_label(egui::Color32::ORANGE, "opaque.");
+            });
+            ui.horizontal(|ui| {
+                if ui.small_button("Open").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("image", &["png", "jpg", "jpeg"])
+                        .pick_file()
+                    {
+                        match image::open(&path) {
+                            Ok(i) => {
+                                *img = Some(i.to_luma8());
+                                map.path = Some(path.display().to_string());
+                            }
+                            Err(e) => {
+                                *status_message = format!("Could Not Load Opacity Map: {}", e)
+                            }
+                        }
+                    }
+                }
+                if let Some(file_name) = map.path.as_ref().and_then(|p| {
+                    PathBuf::from(p)
+                        .file_name()
+                        .map(|f| f.to_string_lossy().to_string())
+                }) {
+                    ui.label(file_name);
+                }
+            });
+            ui.end_row();
+        }
+    }
+
+    if map.kind != OpacityKind::Constant {
+        ui.label("Invert");
+        ui.add(egui::Checkbox::new(&mut map.invert, ""));
+        ui.end_row();
+    }
+}
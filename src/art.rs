@@ -1,15 +1,27 @@
-use crate::core::{App, BlendMode, Combine, ImgGrid, LineColor, SortBy, SortKey, SortOrder};
+use crate::bands::{hybrid, low_pass, multiband_blend};
+use crate::cells::{cells, Cells};
+use crate::core::{
+    App, BlendMode, BlendSpace, Combine, ImgGrid, KeyGrid, LineColor, MeltDirection, OpacityKind,
+    OpacityMap, Render, SortBy, SortOrder,
+};
+use crate::dither::{dither, quantizer};
+use crate::fft::spectral_swap;
+use crate::key::{difference_matte, key_fn, matte_composite};
+use crate::mapfile::resample_map;
 use crate::matrix::Matrix;
 use crate::sortfns::*;
+use crate::swatches::{extract, recolor};
+use crate::transfer::{match_histograms, reinhard};
 use fastrand;
 use image::*;
-use palette::{blend::Blend, LinSrgba, Srgba};
+use palette::{blend::Blend, FromColor, LinSrgba, Oklab, Srgb, Srgba};
 use rayon::prelude::*;
+use std::cmp::Ordering;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use wassily::prelude::*;
 
-pub(crate) fn draw(app: &App, status_tx: Sender<String>) -> RgbaImage {
+pub(crate) fn draw(app: &App, status_tx: Sender<String>) -> Render {
     status_tx
         .send("--------- Mixel 0.1 ---------".to_string())
         .unwrap();
@@ -30,19 +42,18 @@ pub(crate) fn draw(app: &App, status_tx: Sender<String>) -> RgbaImage {
 
     status_tx.send("Blurring Image 1".to_string()).unwrap();
     let mut img = RgbaImage::new(app.width, app.height);
+    let mut out_map = None;
+    // The image the pixel map was applied to.
+    let mut source = None;
+    let mut palette = Vec::new();
 
-    let blurred_img_1 = if app.img_blur_1 > 0.0 {
-        img_1.fast_blur(app.img_blur_1).to_rgba8()
-    } else {
-        img_1.to_rgba8()
-    };
+    let blurred_img_1 = low_pass(&img_1, app.img_blur_1);
 
     status_tx.send("Blurring Image 2".to_string()).unwrap();
-    let blurred_img_2 = if app.img_blur_2 > 0.0 {
-        img_2.fast_blur(app.img_blur_2).to_rgba8()
-    } else {
-        img_2.to_rgba8()
-    };
+    let blurred_img_2 = low_pass(&img_2, app.img_blur_2);
+    // Build the quantizer here, since some of the arms below move
+    // `blurred_img_2`.
+    let quantize = app.quantize.then(|| quantizer(app, &blurred_img_2));
 
     match app.combine {
         Combine::Warp => {
@@ -86,138 +97,226 @@ pub(crate) fn draw(app: &App, status_tx: Sender<String>) -> RgbaImage {
             status_tx.send("Sorting Image 1".to_string()).unwrap();
             let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
             let img_2 = DynamicImage::ImageRgba8(blurred_img_2);
-            let sort_fn = match app.sort_key {
-                SortKey::Lightness => luma,
-                SortKey::Hue => hue,
-                SortKey::Saturation => sat,
-                SortKey::MaxRgb => max_rgb,
-                SortKey::MinRgb => min_rgb,
-                SortKey::Rg => r_g,
-                SortKey::Gb => g_b,
-                SortKey::Br => b_r,
-                SortKey::WrappedHue => wrapped_hue,
-                SortKey::HueSat => hue_sat,
-                SortKey::LumaSat => luma_sat,
-                SortKey::Chroma => chroma,
-            };
-            let px_map = match app.sort_by {
-                SortBy::Row => pixel_map_row(&img_1, sort_fn, app.row_sort_order, None),
-                SortBy::Column => pixel_map_column(&img_1, sort_fn, app.col_sort_order, None),
-                SortBy::RowCol => {
-                    let pm = pixel_map_row(&img_1, sort_fn, app.row_sort_order, None);
-                    pixel_map_column(&img_1, sort_fn, app.col_sort_order, Some(pm))
-                }
-                SortBy::ColRow => {
-                    let pm = pixel_map_column(&img_1, sort_fn, app.col_sort_order, None);
-                    pixel_map_row(&img_1, sort_fn, app.row_sort_order, Some(pm))
-                }
-            };
+            let px_map = pixel_map(&img_1, &sort_fn(app), app);
+            let px_map = partial_pixel_map(&px_map, strength_fn(app));
             status_tx.send("Unsorting Image 2".to_string()).unwrap();
             img = pixel_unsort(&img_2, &px_map);
+            out_map = Some(px_map);
+            source = Some(img_2.into_rgba8());
         }
         Combine::Sort => {
             status_tx.send("Sorting Image".to_string()).unwrap();
             let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
-            let sort_fn = match app.sort_key {
-                SortKey::Lightness => luma,
-                SortKey::Hue => hue,
-                SortKey::Saturation => sat,
-                SortKey::MaxRgb => max_rgb,
-                SortKey::MinRgb => min_rgb,
-                SortKey::Rg => r_g,
-                SortKey::Gb => g_b,
-                SortKey::Br => b_r,
-                SortKey::WrappedHue => wrapped_hue,
-                SortKey::HueSat => hue_sat,
-                SortKey::LumaSat => luma_sat,
-                SortKey::Chroma => chroma,
+            let px_map = sort_map(&img_1, &sort_fn(app), app);
+            let px_map = partial_pixel_map(&px_map, strength_fn(app));
+            status_tx.send("Sorting Image 1".to_string()).unwrap();
+            img = pixel_unsort(&img_1, &px_map);
+            out_map = Some(px_map);
+            source = Some(img_1.into_rgba8());
+        }
+        Combine::Melt => {
+            status_tx.send("Melting Image".to_string()).unwrap();
+            let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
+            let keys = sort_keys(&img_1, &sort_fn(app));
+            let px_map = melt_map(
+                &keys,
+                app.melt_order,
+                app.melt_direction,
+                key_threshold(&keys, app.melt_threshold),
+                run_length_fn(app),
+            );
+            let px_map = partial_pixel_map(&px_map, strength_fn(app));
+            img = pixel_unsort(&img_1, &px_map);
+            out_map = Some(px_map);
+            source = Some(img_1.into_rgba8());
+        }
+        Combine::CrossSort => {
+            status_tx.send("Sorting Image 2".to_string()).unwrap();
+            let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
+            let img_2 = DynamicImage::ImageRgba8(blurred_img_2);
+            let px_map = sort_map(&img_2, &sort_fn(app), app);
+            let px_map = partial_pixel_map(&px_map, strength_fn(app));
+            status_tx.send("Sorting Image 1".to_string()).unwrap();
+            img = pixel_unsort(&img_1, &px_map);
+            out_map = Some(px_map);
+            source = Some(img_1.into_rgba8());
+        }
+        Combine::Transfer => {
+            status_tx.send("Sorting Image 1".to_string()).unwrap();
+            let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
+            let img_2 = DynamicImage::ImageRgba8(blurred_img_2);
+            let sort_fn = sort_fn(app);
+            let (map_1, map_2) = if app.transfer_whole {
+                let order = app.row_sort_order;
+                let map_1 = sort_map_image(&sort_keys(&img_1, &sort_fn), order);
+                status_tx.send("Sorting Image 2".to_string()).unwrap();
+                (map_1, sort_map_image(&sort_keys(&img_2, &sort_fn), order))
+            } else {
+                let map_1 = sort_map(&img_1, &sort_fn, app);
+                status_tx.send("Sorting Image 2".to_string()).unwrap();
+                (map_1, sort_map(&img_2, &sort_fn, app))
             };
-            let px_sort = match app.sort_by {
-                SortBy::Row => pixel_sort_row(&img_1, sort_fn, app.row_sort_order),
-                SortBy::Column => pixel_sort_column(&img_1, sort_fn, app.col_sort_order),
-                SortBy::RowCol => {
-                    let pm = pixel_sort_row(&img_1, sort_fn, app.row_sort_order);
-                    pixel_sort_column(&DynamicImage::ImageRgba8(pm), sort_fn, app.col_sort_order)
+            let px_map = partial_pixel_map(&transfer_map(&map_1, &map_2), strength_fn(app));
+            status_tx.send("Transferring Image 2".to_string()).unwrap();
+            img = pixel_unsort(&img_2, &px_map);
+            out_map = Some(px_map);
+            source = Some(img_2.into_rgba8());
+        }
+        Combine::Remap => {
+            let img_1 = DynamicImage::ImageRgba8(blurred_img_1);
+            let (w, h) = (app.width as usize, app.height as usize);
+            match app.loaded_map.as_deref().map(|m| resample_map(m, w, h)) {
+                Some(Ok(px_map)) => {
+                    status_tx.send("Remapping Image 1".to_string()).unwrap();
+                    img = pixel_unsort(&img_1, &px_map);
+                    out_map = Some(px_map);
+                    source = Some(img_1.into_rgba8());
+                }
+                Some(Err(e)) => {
+                    status_tx.send(format!("Could Not Remap: {}", e)).unwrap();
+                    img = img_1.into_rgba8();
                 }
-                SortBy::ColRow => {
-                    let pm = pixel_sort_column(&img_1, sort_fn, app.col_sort_order);
-                    pixel_sort_row(&DynamicImage::ImageRgba8(pm), sort_fn, app.row_sort_order)
+                None => {
+                    status_tx.send("No Pixel Map Loaded".to_string()).unwrap();
+                    img = img_1.into_rgba8();
                 }
+            }
+        }
+        Combine::Hybrid => {
+            status_tx.send("Splitting Frequencies".to_string()).unwrap();
+            let (low, high) = if app.hybrid_swap {
+                (&blurred_img_2, &blurred_img_1)
+            } else {
+                (&blurred_img_1, &blurred_img_2)
             };
-            status_tx.send("Sorting Image 1".to_string()).unwrap();
-            img = px_sort;
+            img = hybrid(low, high, app.hybrid_cutoff, app.hybrid_detail);
         }
-        rest @ (Combine::Blend | Combine::Divide | Combine::Mix) => {
-            let opts = NoiseOpts::default()
-                .scales(5.0)
-                .width(app.width as f32)
-                .height(app.height as f32);
-
-            let nf = Fbm::<Perlin>::default()
-                .set_seed(13)
-                .set_octaves(app.octaves);
-
-            let opts2 = NoiseOpts::default()
-                .scales(5.0)
-                .width(app.width as f32)
-                .height(app.height as f32);
-
-            let nf2 = Fbm::<Perlin>::default().set_seed(23).set_octaves(4);
+        Combine::Reinhard => {
+            status_tx.send("Transferring Colors".to_string()).unwrap();
+            img = reinhard(
+                &blurred_img_1,
+                &blurred_img_2,
+                app.reinhard_strength,
+                app.reinhard_luma,
+            );
+        }
+        Combine::Histogram => {
+            status_tx.send("Matching Histograms".to_string()).unwrap();
+            img = match_histograms(
+                &blurred_img_1,
+                &blurred_img_2,
+                app.histogram_space,
+                app.histogram_strength,
+            );
+        }
+        Combine::Palette => {
+            status_tx.send("Extracting Palette".to_string()).unwrap();
+            palette = extract(
+                &blurred_img_2,
+                app.palette_size as usize,
+                app.palette_method,
+            );
+            status_tx.send("Recoloring Image 1".to_string()).unwrap();
+            img = recolor(&blurred_img_1, &palette, app.palette_mapping);
+        }
+        Combine::Spectrum => {
+            status_tx.send("Transforming Images".to_string()).unwrap();
+            let (phase, magnitude) = if app.spectrum_swap {
+                (&blurred_img_2, &blurred_img_1)
+            } else {
+                (&blurred_img_1, &blurred_img_2)
+            };
+            img = spectral_swap(phase, magnitude, app.spectrum_mix, app.spectrum_luma);
+        }
+        Combine::Divide if app.multiband => {
+            status_tx.send("Blending Pyramids".to_string()).unwrap();
+            img = multiband_blend(
+                &blurred_img_1,
+                &blurred_img_2,
+                &mask_image(app),
+                app.multiband_levels as usize,
+            );
+        }
+        Combine::Matte => {
+            status_tx
+                .send("Computing Difference Matte".to_string())
+                .unwrap();
+            let matte = difference_matte(app, &blurred_img_1, &blurred_img_2);
+            status_tx.send("Generating Image".to_string()).unwrap();
+            img = if app.matte_show {
+                DynamicImage::ImageLuma8(matte).into_rgba8()
+            } else if app.matte_swap {
+                matte_composite(&blurred_img_2, &blurred_img_1, &matte)
+            } else {
+                matte_composite(&blurred_img_1, &blurred_img_2, &matte)
+            };
+        }
+        rest @ (Combine::Blend | Combine::Divide | Combine::Mix | Combine::Key) => {
+            let in_front = mask_fn(app);
+            let key = key_fn(app);
 
             status_tx.send("Generating Image".to_string()).unwrap();
             let rest = rest;
-            let contamination = app.contamination;
-            let cutoff = app.cutoff;
             let mode = app.mode;
-            let opacity_1 = app.opacity_1;
-            let opacity_2 = app.opacity_2;
+            let opacity_1 = opacity_fn(
+                app,
+                &app.opacity_map_1,
+                app.opacity_1,
+                app.opacity_img_1.as_ref(),
+                51,
+            );
+            let opacity_2 = opacity_fn(
+                app,
+                &app.opacity_map_2,
+                app.opacity_2,
+                app.opacity_img_2.as_ref(),
+                53,
+            );
+            let space = app.blend_space;
             img.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
                 let pixel;
                 match rest {
                     Combine::Divide => {
-                        if noise2d(&nf, &opts, x as f32, y as f32)
-                            + noise2d(&nf2, &opts2, x as f32, y as f32)
-                                * contamination
-                                * (0.5 - fastrand::f32())
-                                / (1.0 + 0.5 * contamination)
-                            > cutoff
-                        {
+                        if in_front(x, y) {
                             pixel = *blurred_img_1.get_pixel(x, y);
                         } else {
                             pixel = *blurred_img_2.get_pixel(x, y);
                         }
                     }
+                    Combine::Key => {
+                        pixel = key(
+                            *blurred_img_1.get_pixel(x, y),
+                            *blurred_img_2.get_pixel(x, y),
+                        );
+                    }
                     Combine::Blend => {
                         pixel = blend(
                             *blurred_img_1.get_pixel(x, y),
                             *blurred_img_2.get_pixel(x, y),
                             mode,
-                            opacity_1,
-                            opacity_2,
+                            opacity_1(x, y),
+                            opacity_2(x, y),
+                            space,
                         );
                     }
                     Combine::Mix => {
-                        if noise2d(&nf, &opts, x as f32, y as f32)
-                            + noise2d(&nf2, &opts2, x as f32, y as f32)
-                                * contamination
-                                * (0.5 - fastrand::f32())
-                                / (1.0 + 0.5 * contamination)
-                            > cutoff
-                        {
+                        if in_front(x, y) {
                             pixel = blend(
                                 *blurred_img_1.get_pixel(x, y),
                                 *blurred_img_2.get_pixel(x, y),
                                 mode,
-                                opacity_1,
-                                opacity_2,
+                                opacity_1(x, y),
+                                opacity_2(x, y),
+                                space,
                             )
                         } else {
                             pixel = blend(
                                 *blurred_img_2.get_pixel(x, y),
                                 *blurred_img_1.get_pixel(x, y),
                                 mode,
-                                opacity_1,
-                                opacity_2,
+                                opacity_1(x, y),
+                                opacity_2(x, y),
+                                space,
                             )
                         };
                     }
@@ -231,53 +330,231 @@ pub(crate) fn draw(app: &App, status_tx: Sender<String>) -> RgbaImage {
         }
     }
 
-    status_tx.send("Creating Canvas".to_string()).unwrap();
+    // Quantize an output the same way as the image, when quantizing is on.
+    // The source is left as it is since each exported animation frame made
+    // from it is quantized, so the last frame matches the image.
+    let quantized = |img: RgbaImage| match &quantize {
+        Some(q) => dither(&img, q, app.dither_kind, app.dither_amount),
+        None => img,
+    };
+    if quantize.is_some() {
+        status_tx.send("Dithering Image".to_string()).unwrap();
+        img = quantized(img);
+    }
+
+    let mut extras = Vec::new();
+    if let Some(px_map) = &out_map {
+        for (i, extra) in app.extra_imgs.iter().enumerate() {
+            status_tx
+                .send(format!("Mapping Extra Image {}", i + 1))
+                .unwrap();
+            let extra = DynamicImage::ImageRgba8(extra.clone()).resize_exact(
+                app.width,
+                app.height,
+                image::imageops::FilterType::Lanczos3,
+            );
+            extras.push(quantized(pixel_unsort(&extra, px_map)));
+        }
+        // The mask is a matte rather than a picture, so it is not quantized.
+        if app.sort_mask {
+            status_tx.send("Mapping Mask".to_string()).unwrap();
+            let mask = DynamicImage::ImageRgba8(mask_image(app));
+            extras.push(pixel_unsort(&mask, px_map));
+        }
+    }
+
+    status_tx.send("Drawing Overlay".to_string()).unwrap();
+    let img = overlay(&Overlay::new(app), img);
+    status_tx.send("Image Generated".to_string()).unwrap();
+
+    Render {
+        img,
+        px_map: out_map,
+        source,
+        extras,
+        palette,
+        quantizer: quantize,
+    }
+}
+
+// The settings for the screen lines and grain drawn over each output, apart
+// from the app so an export thread can take them on their own.
+#[derive(Debug, Clone)]
+pub(crate) struct Overlay {
+    screen: bool,
+    spacing: f32,
+    line_color: LineColor,
+    thickness: f32,
+    subdivisions: u32,
+    min_opacity: f32,
+    max_opacity: f32,
+    grain_scale: f32,
+    grain_factor: f32,
+}
+
+impl Overlay {
+    pub(crate) fn new(app: &App) -> Self {
+        Self {
+            screen: app.screen,
+            spacing: app.spacing,
+            line_color: app.line_color,
+            thickness: app.thickness,
+            subdivisions: app.subdivisions,
+            min_opacity: app.min_opacity,
+            max_opacity: app.max_opacity,
+            grain_scale: app.grain_scale,
+            grain_factor: app.grain_factor,
+        }
+    }
+}
+
+// Draw the screen lines and grain over the image.
+pub(crate) fn overlay(ov: &Overlay, img: RgbaImage) -> RgbaImage {
     let mut canvas = Canvas::from_image(&DynamicImage::ImageRgba8(img));
-    let linecolor = if app.line_color == LineColor::Black {
+    let linecolor = if ov.line_color == LineColor::Black {
         *BLACK
     } else {
         *WHITE
     };
-    let mut i = app.spacing;
+    let mut i = ov.spacing;
 
-    status_tx.send("Drawing Overlay".to_string()).unwrap();
-    if app.screen {
+    if ov.screen {
         while i < canvas.h_f32() {
             let v0 = pt(0, i);
             let v1 = pt(canvas.width(), i);
             let mut fl = FadeLine::new(v0, v1, 98731 + i as u64)
-                .subdivisions(app.subdivisions)
-                .thickness(app.thickness)
-                .min_opacity(app.min_opacity)
-                .max_opacity(app.max_opacity)
+                .subdivisions(ov.subdivisions)
+                .thickness(ov.thickness)
+                .min_opacity(ov.min_opacity)
+                .max_opacity(ov.max_opacity)
                 .color(linecolor);
             fl.draw(&mut canvas);
-            i += app.spacing;
+            i += ov.spacing;
         }
-        i = app.spacing;
+        i = ov.spacing;
         while i < canvas.w_f32() {
             let v0 = pt(i, 0);
             let v1 = pt(i, canvas.height());
             let mut fl = FadeLine::new(v0, v1, 98731 + i as u64)
-                .subdivisions(app.subdivisions)
-                .thickness(app.thickness)
-                .min_opacity(app.min_opacity)
-                .max_opacity(app.max_opacity)
+                .subdivisions(ov.subdivisions)
+                .thickness(ov.thickness)
+                .min_opacity(ov.min_opacity)
+                .max_opacity(ov.max_opacity)
                 .color(linecolor);
             fl.draw(&mut canvas);
-            i += app.spacing;
+            i += ov.spacing;
         }
     }
-    status_tx.send("Image Generated".to_string()).unwrap();
 
-    if app.grain_scale > 0.0 && app.grain_factor > 0.0 {
-        let gr = Grain::new(500, 500, app.grain_scale, app.grain_factor);
+    if ov.grain_scale > 0.0 && ov.grain_factor > 0.0 {
+        let gr = Grain::new(500, 500, ov.grain_scale, ov.grain_factor);
         gr.canvas_grain(&mut canvas);
     }
 
     canvas_to_rgba_image(&canvas)
 }
 
+// The Divide and Mix mask, true where image 1 is in front.
+fn mask_fn(app: &App) -> impl Fn(u32, u32) -> bool + Sync {
+    let opts = NoiseOpts::default()
+        .scales(5.0)
+        .width(app.width as f32)
+        .height(app.height as f32);
+
+    let nf = Fbm::<Perlin>::default()
+        .set_seed(13)
+        .set_octaves(app.octaves);
+
+    let opts2 = NoiseOpts::default()
+        .scales(5.0)
+        .width(app.width as f32)
+        .height(app.height as f32);
+
+    let nf2 = Fbm::<Perlin>::default().set_seed(23).set_octaves(4);
+
+    let contamination = app.contamination;
+    let cutoff = app.cutoff;
+    move |x, y| {
+        noise2d(&nf, &opts, x as f32, y as f32)
+            + noise2d(&nf2, &opts2, x as f32, y as f32)
+                * contamination
+                * (0.5 - hash_unit(x, y, 29))
+                / (1.0 + 0.5 * contamination)
+            > cutoff
+    }
+}
+
+// A value in 0..1 that depends only on the pixel and the seed, so the mask
+// is the same however the pixels are split across threads.
+fn hash_unit(x: u32, y: u32, seed: u32) -> f32 {
+    let mut h =
+        x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ seed.wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+// The opacity of an image at each pixel, its opacity setting scaled by the
+// gradient, noise field or mask image of its opacity map.
+fn opacity_fn(
+    app: &App,
+    map: &OpacityMap,
+    opacity: u8,
+    mask: Option<&GrayImage>,
+    seed: u32,
+) -> impl Fn(u32, u32) -> u8 + Sync {
+    let (w, h) = (app.width as f32, app.height as f32);
+    let opts = NoiseOpts::default().scales(map.scale).width(w).height(h);
+    let nf = Fbm::<Perlin>::default()
+        .set_seed(seed)
+        .set_octaves(app.octaves);
+    let (sin, cos) = map.angle.to_radians().sin_cos();
+    // Half the extent of the image along the gradient direction.
+    let extent = 0.5 * (cos.abs() * w + sin.abs() * h);
+    let radius = 0.5 * w.hypot(h);
+    let mask = match (map.kind, mask) {
+        (OpacityKind::Image, Some(mask)) => Some(image::imageops::resize(
+            mask,
+            app.width,
+            app.height,
+            image::imageops::FilterType::Lanczos3,
+        )),
+        _ => None,
+    };
+    let kind = map.kind;
+    let invert = map.invert;
+    move |x, y| {
+        let (dx, dy) = (x as f32 + 0.5 - 0.5 * w, y as f32 + 0.5 - 0.5 * h);
+        let t = match kind {
+            OpacityKind::Constant => return opacity,
+            OpacityKind::Linear => 0.5 + 0.5 * (dx * cos + dy * sin) / extent,
+            OpacityKind::Radial => 1.0 - dx.hypot(dy) / radius,
+            OpacityKind::Noise => unit_noise(&nf, &opts, x as f32, y as f32),
+            OpacityKind::Image => match &mask {
+                Some(mask) => mask.get_pixel(x, y)[0] as f32 / 255.0,
+                None => return opacity,
+            },
+        }
+        .clamp(0.0, 1.0);
+        let t = if invert { 1.0 - t } else { t };
+        (opacity as f32 * t).round() as u8
+    }
+}
+
+// The Divide and Mix mask as an image, white where image 1 is in front.
+fn mask_image(app: &App) -> RgbaImage {
+    let in_front = mask_fn(app);
+    let mut mask = RgbaImage::new(app.width, app.height);
+    mask.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let v = if in_front(x, y) { 255 } else { 0 };
+        *px = Rgba([v, v, v, 255]);
+    });
+    mask
+}
+
 fn canvas_to_rgba_image(canvas: &Canvas) -> RgbaImage {
     let pixmap = &canvas.pixmap;
     let pixels = pixmap.data();
@@ -313,11 +590,153 @@ fn normal_blend(src: LinSrgba, dst: LinSrgba) -> LinSrgba {
     LinSrgba::new(red, green, blue, alpha)
 }
 
-fn blend(c1: Rgba<u8>, c2: Rgba<u8>, mode: BlendMode, opacity_1: u8, opacity_2: u8) -> Rgba<u8> {
-    let c1 = Srgba::from_components((c1.0[0], c1.0[1], c1.0[2], opacity_1));
-    let c2 = Srgba::from_components((c2.0[0], c2.0[1], c2.0[2], opacity_2));
-    let lin_color1: LinSrgba = c1.into_linear();
-    let lin_color2: LinSrgba = c2.into_linear();
+// Blend modes that mix the channels, from the W3C compositing spec.
+fn non_separable_blend(src: LinSrgba, dst: LinSrgba, mode: BlendMode) -> LinSrgba {
+    let cs = [src.red, src.green, src.blue];
+    let cb = [dst.red, dst.green, dst.blue];
+    let b = match mode {
+        BlendMode::Hue => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        BlendMode::Saturation => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        BlendMode::Color => set_lum(cs, lum(cb)),
+        BlendMode::Luminosity => set_lum(cb, lum(cs)),
+        BlendMode::DarkerColor if lum(cs) < lum(cb) => cs,
+        BlendMode::LighterColor if lum(cs) > lum(cb) => cs,
+        BlendMode::DarkerColor | BlendMode::LighterColor => cb,
+        _ => unreachable!(),
+    };
+    composite(src, dst, b)
+}
+
+// Blend modes that work on each channel alone, with the backdrop channel
+// first and the source second.
+fn separable_blend(src: LinSrgba, dst: LinSrgba, mode: BlendMode) -> LinSrgba {
+    let f: fn(f32, f32) -> f32 = match mode {
+        BlendMode::LinearBurn => |b, s| (b + s - 1.0).max(0.0),
+        BlendMode::LinearDodge => |b, s| (b + s).min(1.0),
+        BlendMode::LinearLight => |b, s| (b + 2.0 * s - 1.0).clamp(0.0, 1.0),
+        BlendMode::VividLight => |b, s| {
+            if s <= 0.5 {
+                color_burn(b, 2.0 * s)
+            } else {
+                color_dodge(b, 2.0 * s - 1.0)
+            }
+        },
+        BlendMode::PinLight => |b, s| {
+            if s <= 0.5 {
+                b.min(2.0 * s)
+            } else {
+                b.max(2.0 * s - 1.0)
+            }
+        },
+        BlendMode::HardMix => |b, s| if b + s >= 1.0 { 1.0 } else { 0.0 },
+        BlendMode::Subtract => |b, s| (b - s).max(0.0),
+        BlendMode::Divide => |b, s| {
+            if s <= 0.0 {
+                if b <= 0.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            } else {
+                (b / s).min(1.0)
+            }
+        },
+        _ => unreachable!(),
+    };
+    let b = [
+        f(dst.red, src.red),
+        f(dst.green, src.green),
+        f(dst.blue, src.blue),
+    ];
+    composite(src, dst, b)
+}
+
+fn color_dodge(b: f32, s: f32) -> f32 {
+    if b <= 0.0 {
+        0.0
+    } else if s >= 1.0 {
+        1.0
+    } else {
+        (b / (1.0 - s)).min(1.0)
+    }
+}
+
+fn color_burn(b: f32, s: f32) -> f32 {
+    if b >= 1.0 {
+        1.0
+    } else if s <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - b) / s).min(1.0)
+    }
+}
+
+// Composite the source over the backdrop where `b` is the blended color
+// for the parts they overlap.
+fn composite(src: LinSrgba, dst: LinSrgba, b: [f32; 3]) -> LinSrgba {
+    let cs = [src.red, src.green, src.blue];
+    let cb = [dst.red, dst.green, dst.blue];
+    let (a_s, a_b) = (src.alpha, dst.alpha);
+    let alpha = a_s + a_b - a_s * a_b;
+    if alpha <= 0.0 {
+        return LinSrgba::new(0.0, 0.0, 0.0, 0.0);
+    }
+    let mix = |i: usize| {
+        (cs[i] * a_s * (1.0 - a_b) + cb[i] * a_b * (1.0 - a_s) + b[i] * a_s * a_b) / alpha
+    };
+    LinSrgba::new(mix(0), mix(1), mix(2), alpha)
+}
+
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+// Bring a color back into gamut keeping its luminosity.
+fn clip_color(c: [f32; 3]) -> [f32; 3] {
+    let l = lum(c);
+    let n = c[0].min(c[1]).min(c[2]);
+    let x = c[0].max(c[1]).max(c[2]);
+    let mut c = c;
+    if n < 0.0 {
+        c = c.map(|v| l + (v - l) * l / (l - n));
+    }
+    if x > 1.0 {
+        c = c.map(|v| l + (v - l) * (1.0 - l) / (x - l));
+    }
+    c
+}
+
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(c);
+    clip_color(c.map(|v| v + d))
+}
+
+fn sat(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3] {
+    let max = c[0].max(c[1]).max(c[2]);
+    let min = c[0].min(c[1]).min(c[2]);
+    if max > min {
+        c.map(|v| (v - min) * s / (max - min))
+    } else {
+        [0.0; 3]
+    }
+}
+
+fn blend(
+    c1: Rgba<u8>,
+    c2: Rgba<u8>,
+    mode: BlendMode,
+    opacity_1: u8,
+    opacity_2: u8,
+    space: BlendSpace,
+) -> Rgba<u8> {
+    let c1 = Srgba::from_components((c1.0[0], c1.0[1], c1.0[2], opacity_1)).into_format();
+    let c2 = Srgba::from_components((c2.0[0], c2.0[1], c2.0[2], opacity_2)).into_format();
+    let lin_color1 = to_blend_space(c1, space);
+    let lin_color2 = to_blend_space(c2, space);
     let blended_lin_color = match mode {
         BlendMode::Multiply => lin_color1.multiply(lin_color2),
         BlendMode::Screen => lin_color1.screen(lin_color2),
@@ -331,99 +750,878 @@ fn blend(c1: Rgba<u8>, c2: Rgba<u8>, mode: BlendMode, opacity_1: u8, opacity_2:
         BlendMode::Difference => lin_color1.difference(lin_color2),
         BlendMode::Exclusion => lin_color1.exclusion(lin_color2),
         BlendMode::Normal => normal_blend(lin_color1, lin_color2),
+        BlendMode::LinearBurn
+        | BlendMode::LinearDodge
+        | BlendMode::LinearLight
+        | BlendMode::VividLight
+        | BlendMode::PinLight
+        | BlendMode::HardMix
+        | BlendMode::Subtract
+        | BlendMode::Divide => separable_blend(lin_color1, lin_color2, mode),
+        BlendMode::Hue
+        | BlendMode::Saturation
+        | BlendMode::Color
+        | BlendMode::Luminosity
+        | BlendMode::DarkerColor
+        | BlendMode::LighterColor => non_separable_blend(lin_color1, lin_color2, mode),
     };
-    let blended_srgba = Srgba::from_linear(blended_lin_color);
-    srgba_to_rgba_u8(blended_srgba)
+    srgba_to_rgba_u8(from_blend_space(blended_lin_color, space))
+}
+
+// Carry a color into the space the blend modes work in. Whatever the space the
+// channels are held in a LinSrgba, with Oklab's a and b moved into 0..1.
+fn to_blend_space(c: Srgba, space: BlendSpace) -> LinSrgba {
+    match space {
+        BlendSpace::Linear => c.into_linear(),
+        BlendSpace::Srgb => LinSrgba::new(c.red, c.green, c.blue, c.alpha),
+        BlendSpace::Oklab => {
+            let lab = Oklab::from_color(c.color);
+            LinSrgba::new(lab.l, lab.a + 0.5, lab.b + 0.5, c.alpha)
+        }
+    }
+}
+
+fn from_blend_space(c: LinSrgba, space: BlendSpace) -> Srgba {
+    match space {
+        BlendSpace::Linear => Srgba::from_linear(c),
+        BlendSpace::Srgb => Srgba::new(c.red, c.green, c.blue, c.alpha),
+        BlendSpace::Oklab => {
+            let rgb = Srgb::from_color(Oklab::new(c.red, c.green - 0.5, c.blue - 0.5));
+            Srgba::new(
+                rgb.red.clamp(0.0, 1.0),
+                rgb.green.clamp(0.0, 1.0),
+                rgb.blue.clamp(0.0, 1.0),
+                c.alpha,
+            )
+        }
+    }
+}
+
+// The pixel map of the image for the app's sort direction.
+fn pixel_map(img: &DynamicImage, f: &SortFn, app: &App) -> ImgGrid {
+    let keys = sort_keys(img, f);
+    let cells = cells(app, keys.width, keys.height);
+    let c = cells.as_ref();
+    let (row, col) = (app.row_sort_order, app.col_sort_order);
+    match sort_by(app, c) {
+        SortBy::Row => pixel_map_row(&keys, row, c, None),
+        SortBy::Column => pixel_map_column(&keys, col, c, None),
+        SortBy::RowCol => {
+            let pm = pixel_map_row(&keys, row, c, None);
+            pixel_map_column(&keys, col, c, Some(pm))
+        }
+        SortBy::ColRow => {
+            let pm = pixel_map_column(&keys, col, c, None);
+            pixel_map_row(&keys, row, c, Some(pm))
+        }
+    }
+}
+
+// The sort map of the image for the app's sort direction.
+fn sort_map(img: &DynamicImage, f: &SortFn, app: &App) -> ImgGrid {
+    let keys = sort_keys(img, f);
+    let cells = cells(app, keys.width, keys.height);
+    let c = cells.as_ref();
+    let (row, col) = (app.row_sort_order, app.col_sort_order);
+    match sort_by(app, c) {
+        SortBy::Row => sort_map_row(&keys, row, c, None),
+        SortBy::Column => sort_map_column(&keys, col, c, None),
+        SortBy::RowCol => {
+            let pm = sort_map_row(&keys, row, c, None);
+            sort_map_column(&keys, col, c, Some(pm))
+        }
+        SortBy::ColRow => {
+            let pm = sort_map_column(&keys, col, c, None);
+            sort_map_row(&keys, row, c, Some(pm))
+        }
+    }
+}
+
+// Randomized cells each pick their own direction, so both passes are needed.
+fn sort_by(app: &App, cells: Option<&Cells>) -> SortBy {
+    match cells {
+        Some(c) if c.random.is_some() => SortBy::RowCol,
+        _ => app.sort_by,
+    }
+}
+
+// Evaluate the sort function once for every pixel of the image.
+pub fn sort_keys(img: &DynamicImage, f: &SortFn) -> KeyGrid {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let data = (0..w * h)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = ((i % w) as u32, (i / w) as u32);
+            f(x, y, img.get_pixel(x, y))
+        })
+        .collect();
+    Matrix::new(w, h, data)
+}
+
+// Compare two image locations by their cached keys.
+fn key_cmp(
+    keys: &KeyGrid,
+    order: SortOrder,
+) -> impl Fn(&(usize, usize), &(usize, usize)) -> Ordering + Sync + '_ {
+    let dir = order.dir();
+    move |a, b| (dir * keys[a.1][a.0]).total_cmp(&(dir * keys[b.1][b.0]))
+}
+
+fn identity_grid(keys: &KeyGrid) -> ImgGrid {
+    Matrix::generate(keys.width, keys.height, |x, y| (x, y))
 }
 
 // Generate an image grid with the location of each pixel in the image.
-// Sort the pixels in each row by the sort function.
+// Sort the pixels in each row by their keys.
 pub fn pixel_map_row(
-    img: &DynamicImage,
-    f: SortFn,
+    keys: &KeyGrid,
     order: SortOrder,
+    cells: Option<&Cells>,
     grid: Option<ImgGrid>,
 ) -> ImgGrid {
-    let mut px_map = match grid {
-        Some(g) => g,
-        None => Matrix::generate(img.width() as usize, img.height() as usize, |x, y| (x, y)),
-    };
-    for y in 0..px_map.height {
-        let mut row = px_map[y].to_vec();
-        row.par_sort_by_key(|x| order.dir() * f(img.get_pixel(x.0 as u32, x.1 as u32)));
-        let mut indices = (0..row.len()).collect::<Vec<_>>();
-        indices.par_sort_by_key(|i| row[*i].0);
-        let row1 = indices.par_iter().map(|i| (*i, y)).collect::<Vec<_>>();
-        px_map[y].par_iter_mut().enumerate().for_each(|(i, e)| {
-            *e = row1[i];
-        });
-    }
+    let mut px_map = grid.unwrap_or_else(|| identity_grid(keys));
+    rank_runs(&mut px_map, keys, order, cells, true);
     px_map
 }
 
 // Generate an image grid with the location of each pixel in the image.
-// Sort the pixels in each column by the sort function.
+// Sort the pixels in each column by their keys.
 pub fn pixel_map_column(
-    img: &DynamicImage,
-    f: SortFn,
+    keys: &KeyGrid,
     order: SortOrder,
+    cells: Option<&Cells>,
     grid: Option<ImgGrid>,
 ) -> ImgGrid {
-    let mut px_map = match grid {
-        Some(g) => g,
-        None => Matrix::generate(img.width() as usize, img.height() as usize, |x, y| (x, y)),
-    };
-    for x in 0..px_map.width {
-        let mut column = px_map.get_column(x);
-        column.par_sort_by_key(|y| order.dir() * f(img.get_pixel(y.0 as u32, y.1 as u32)));
-        let mut indices = (0..column.len()).collect::<Vec<_>>();
-        indices.par_sort_by_key(|i| column[*i].1);
-        let column1 = indices.par_iter().map(|i| (x, *i)).collect::<Vec<_>>();
-        for y in 0..px_map.height {
-            px_map[y][x] = column1[y]
+    let mut px_map = grid.unwrap_or_else(|| identity_grid(keys)).transpose();
+    rank_runs(&mut px_map, keys, order, cells, false);
+    px_map.transpose()
+}
+
+// Call `f` with each run of a row of the grid that lies in a single cell,
+// one row per task, along with the row index, the start of the run and its
+// order. A grid of columns is passed transposed.
+fn for_each_run<F>(
+    px_map: &mut ImgGrid,
+    order: SortOrder,
+    cells: Option<&Cells>,
+    along_rows: bool,
+    f: F,
+) where
+    F: Fn(usize, usize, &mut [(usize, usize)], SortOrder) + Sync,
+{
+    let transposed = cells.filter(|_| !along_rows).map(|c| c.ids.transpose());
+    let ids = transposed.as_ref().or(cells.map(|c| &c.ids));
+    let width = px_map.width;
+    px_map
+        .data
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(i, line)| {
+            let (Some(cells), Some(ids)) = (cells, ids) else {
+                f(i, 0, line, order);
+                return;
+            };
+            let mut start = 0;
+            for run in ids[i].chunk_by(|a, b| a == b) {
+                let (end, cell) = (start + run.len(), run[0] as usize);
+                if cells.sorts(cell, along_rows) {
+                    f(i, start, &mut line[start..end], cells.order(cell, order));
+                }
+                start = end;
+            }
+        });
+}
+
+// Replace each run of the grid by the rank of its pixels.
+fn rank_runs(
+    px_map: &mut ImgGrid,
+    keys: &KeyGrid,
+    order: SortOrder,
+    cells: Option<&Cells>,
+    along_rows: bool,
+) {
+    for_each_run(px_map, order, cells, along_rows, |i, start, run, order| {
+        let mut sorted = run.to_vec();
+        sorted.sort_by(key_cmp(keys, order));
+        for (rank, p) in sorted.into_iter().enumerate() {
+            if along_rows {
+                run[p.0 - start] = (start + rank, i);
+            } else {
+                run[p.1 - start] = (i, start + rank);
+            }
         }
-    }
+    });
+}
+
+// Generate an image grid with the location each pixel of the sorted image
+// comes from. Sort the pixels in each row by their keys.
+pub fn sort_map_row(
+    keys: &KeyGrid,
+    order: SortOrder,
+    cells: Option<&Cells>,
+    grid: Option<ImgGrid>,
+) -> ImgGrid {
+    let mut px_map = grid.unwrap_or_else(|| identity_grid(keys));
+    for_each_run(&mut px_map, order, cells, true, |_, _, run, order| {
+        run.sort_by(key_cmp(keys, order))
+    });
     px_map
 }
 
-#[allow(dead_code)]
-// Pixel sort a DynamicImage by rows.
-pub fn pixel_sort_row(img: &DynamicImage, f: SortFn, order: SortOrder) -> RgbaImage {
-    let mut data: Vec<u8> = Vec::with_capacity(16 * img.width() as usize * img.height() as usize);
-    let buffer = img.to_rgba8();
-    for buf_row in buffer.rows() {
-        let mut row = Vec::with_capacity(buf_row.len());
-        for p in buf_row {
-            row.push(*p);
-        }
-        row.par_sort_by_key(|p| order.dir() * f(*p));
-        for p in row {
-            for c in p.channels() {
-                data.push(*c);
+// Generate an image grid with the location each pixel of the sorted image
+// comes from. Sort the pixels in each column by their keys.
+pub fn sort_map_column(
+    keys: &KeyGrid,
+    order: SortOrder,
+    cells: Option<&Cells>,
+    grid: Option<ImgGrid>,
+) -> ImgGrid {
+    let mut px_map = grid.unwrap_or_else(|| identity_grid(keys)).transpose();
+    for_each_run(&mut px_map, order, cells, false, |_, _, run, order| {
+        run.sort_by(key_cmp(keys, order))
+    });
+    px_map.transpose()
+}
+
+// Generate an image grid with the location each pixel of the sorted image
+// comes from. Sort all of the pixels in the image, in row major order.
+pub fn sort_map_image(keys: &KeyGrid, order: SortOrder) -> ImgGrid {
+    let mut px_map = identity_grid(keys);
+    px_map.data.par_sort_by(key_cmp(keys, order));
+    px_map
+}
+
+// Generate an image grid with the location each pixel of the melted image
+// comes from. Starting at each pixel whose key is above the threshold, sort
+// the run of pixels that follows it in the melt direction.
+pub fn melt_map<F>(
+    keys: &KeyGrid,
+    order: SortOrder,
+    direction: MeltDirection,
+    threshold: f32,
+    run_length: F,
+) -> ImgGrid
+where
+    F: Fn(usize, usize) -> usize + Sync,
+{
+    let vertical = matches!(direction, MeltDirection::Down | MeltDirection::Up);
+    let reverse = matches!(direction, MeltDirection::Up | MeltDirection::Left);
+    let mut px_map = identity_grid(keys);
+    if vertical {
+        px_map = px_map.transpose();
+    }
+    let cmp = key_cmp(keys, order);
+    let width = px_map.width;
+    px_map.data.par_chunks_mut(width).for_each(|line| {
+        if reverse {
+            line.reverse();
+        }
+        let mut i = 0;
+        while i < line.len() {
+            let (x, y) = line[i];
+            if keys[y][x] > threshold {
+                let end = (i + run_length(x, y).max(1)).min(line.len());
+                line[i..end].sort_by(&cmp);
+                i = end;
+            } else {
+                i += 1;
             }
         }
+        if reverse {
+            line.reverse();
+        }
+    });
+    if vertical {
+        px_map = px_map.transpose();
     }
-    ImageBuffer::from_vec(img.width(), img.height(), data).unwrap()
+    px_map
+}
+
+// The key a fraction `t` of the way from the smallest key to the largest, so
+// one threshold setting works for keys of any range.
+fn key_threshold(keys: &KeyGrid, t: f32) -> f32 {
+    let (min, max) = keys
+        .data
+        .par_iter()
+        .fold(
+            || (f32::INFINITY, f32::NEG_INFINITY),
+            |(lo, hi), &k| (lo.min(k), hi.max(k)),
+        )
+        .reduce(
+            || (f32::INFINITY, f32::NEG_INFINITY),
+            |(lo, hi), (lo2, hi2)| (lo.min(lo2), hi.max(hi2)),
+        );
+    if min > max {
+        return 0.0;
+    }
+    let t = t.clamp(0.0, 1.0);
+    min * (1.0 - t) + max * t
+}
+
+// The length of the run started at each pixel, drawn from a noise field so
+// neighboring runs drip to similar lengths.
+fn run_length_fn(app: &App) -> impl Fn(usize, usize) -> usize + Sync {
+    let opts = NoiseOpts::default()
+        .scales(app.melt_scale)
+        .width(app.width as f32)
+        .height(app.height as f32);
+    let nf = Fbm::<Perlin>::default()
+        .set_seed(41)
+        .set_octaves(app.octaves);
+    let length = app.melt_length;
+    move |x, y| {
+        let n = unit_noise(&nf, &opts, x as f32, y as f32);
+        (length * n).round() as usize
+    }
+}
+
+// Combine the sort maps of two images into a pixel map that puts the pixel of
+// image 2 with the same rank at each position of image 1.
+pub fn transfer_map(map_1: &ImgGrid, map_2: &ImgGrid) -> ImgGrid {
+    let mut px_map = Matrix::fill(map_1.width, map_1.height, (0, 0));
+    for (&(x, y), &p) in map_1.data.iter().zip(map_2.data.iter()) {
+        px_map[y][x] = p;
+    }
+    px_map
+}
+
+// Noise scaled into 0..1, stretched so it saturates at 0 and 1 over large
+// regions.
+fn unit_noise(nf: &Fbm<Perlin>, opts: &NoiseOpts, x: f32, y: f32) -> f32 {
+    (noise2d(nf, opts, x, y) * 2.0 + 0.5).clamp(0.0, 1.0)
+}
+
+// The sort strength at each pixel, optionally modulated by a noise field so
+// that some regions sort fully while others stay intact.
+fn strength_fn(app: &App) -> impl Fn(usize, usize) -> f32 + Sync {
+    let opts = NoiseOpts::default()
+        .scales(app.strength_scale)
+        .width(app.width as f32)
+        .height(app.height as f32);
+    let nf = Fbm::<Perlin>::default()
+        .set_seed(31)
+        .set_octaves(app.octaves);
+    let strength = app.sort_strength;
+    let amount = app.strength_noise;
+    move |x, y| {
+        if amount <= 0.0 {
+            return strength;
+        }
+        let n = unit_noise(&nf, &opts, x as f32, y as f32);
+        strength * (1.0 - amount + amount * n)
+    }
+}
+
+// Move each entry of the pixel map only part of the way from its own
+// position towards its mapped position.
+pub fn partial_pixel_map<F>(px_map: &ImgGrid, strength: F) -> ImgGrid
+where
+    F: Fn(usize, usize) -> f32 + Sync,
+{
+    let width = px_map.width;
+    let data = px_map
+        .data
+        .par_iter()
+        .enumerate()
+        .map(|(i, &(x1, y1))| {
+            let (x, y) = (i % width, i / width);
+            let s = strength(x, y).clamp(0.0, 1.0);
+            let lerp = |a: usize, b: usize| (a as f32 + s * (b as f32 - a as f32)).round() as usize;
+            (lerp(x, x1), lerp(y, y1))
+        })
+        .collect();
+    Matrix::new(px_map.width, px_map.height, data)
+}
+
+#[allow(dead_code)]
+// Pixel sort a DynamicImage by rows.
+pub fn pixel_sort_row(img: &DynamicImage, f: &SortFn, order: SortOrder) -> RgbaImage {
+    pixel_unsort(img, &sort_map_row(&sort_keys(img, f), order, None, None))
 }
 
 #[allow(dead_code)]
 // Pixel sort a DynamicImage by columns.
-pub fn pixel_sort_column(img: &DynamicImage, f: SortFn, order: SortOrder) -> RgbaImage {
-    let rotate_img = img.rotate90();
-    let sorted_img = pixel_sort_row(&rotate_img, f, -order);
-    let dyn_img = DynamicImage::ImageRgba8(sorted_img);
-    dyn_img.rotate270().into_rgba8()
+pub fn pixel_sort_column(img: &DynamicImage, f: &SortFn, order: SortOrder) -> RgbaImage {
+    pixel_unsort(img, &sort_map_column(&sort_keys(img, f), order, None, None))
 }
 
 // Unsort the image using the pixel map.
 pub fn pixel_unsort(img: &DynamicImage, px_map: &ImgGrid) -> RgbaImage {
     let mut out_image = RgbaImage::new(img.width(), img.height());
-    for y in 0..px_map.height {
-        for x in 0..px_map.width {
-            let (x1, y1) = px_map[y][x];
-            let p = img.get_pixel(x1 as u32, y1 as u32);
-            out_image.put_pixel(x as u32, y as u32, p)
+    out_image.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let (x1, y1) = px_map[y as usize][x as usize];
+        *px = img.get_pixel(x1 as u32, y1 as u32);
+    });
+    out_image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::CellLayout;
+    use crate::sortfns::luma;
+    use palette::LinSrgb;
+
+    fn test_image() -> DynamicImage {
+        let mut rng = fastrand::Rng::with_seed(7);
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(13, 9, |_, _| {
+            Rgba([rng.u8(..), rng.u8(..), rng.u8(..), 255])
+        }))
+    }
+
+    fn luma_fn() -> SortFn {
+        Arc::new(|_, _, c| luma(c))
+    }
+
+    #[test]
+    fn sort_map_row_test() {
+        let img = test_image();
+        let f = luma_fn();
+        let px_map = sort_map_row(&sort_keys(&img, &f), SortOrder::Ascending, None, None);
+        let sorted = pixel_unsort(&img, &px_map);
+        for y in 0..sorted.height() {
+            let mut row = (0..sorted.width())
+                .map(|x| img.get_pixel(x, y))
+                .collect::<Vec<_>>();
+            row.sort_by(|a, b| luma(*a).total_cmp(&luma(*b)));
+            for (x, p) in row.into_iter().enumerate() {
+                assert_eq!(*sorted.get_pixel(x as u32, y), p);
+            }
+        }
+    }
+
+    #[test]
+    fn sort_map_column_test() {
+        let img = test_image();
+        let f = luma_fn();
+        let px_map = sort_map_column(&sort_keys(&img, &f), SortOrder::Descending, None, None);
+        let sorted = pixel_unsort(&img, &px_map);
+        for x in 0..sorted.width() {
+            for y in 1..sorted.height() {
+                assert!(luma(*sorted.get_pixel(x, y - 1)) >= luma(*sorted.get_pixel(x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn cross_sort_test() {
+        // Image 1 ordered by the keys of image 2 follows image 2's permutation.
+        let img_1 = test_image();
+        let img_2 = DynamicImage::ImageRgba8(RgbaImage::from_fn(13, 9, |x, _| {
+            let v = (12 - x) as u8 * 20;
+            Rgba([v, v, v, 255])
+        }));
+        let px_map = sort_map_row(
+            &sort_keys(&img_2, &luma_fn()),
+            SortOrder::Ascending,
+            None,
+            None,
+        );
+        let crossed = pixel_unsort(&img_1, &px_map);
+        for y in 0..crossed.height() {
+            for x in 0..crossed.width() {
+                assert_eq!(crossed.get_pixel(x, y), &img_1.get_pixel(12 - x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn transfer_test() {
+        let img_1 = test_image();
+        let img_2 = DynamicImage::ImageRgba8(RgbaImage::from_fn(13, 9, |x, y| {
+            let v = (x * 9 + y) as u8;
+            Rgba([v, v, v, 255])
+        }));
+        let f = luma_fn();
+        let map_1 = sort_map_image(&sort_keys(&img_1, &f), SortOrder::Ascending);
+        let map_2 = sort_map_image(&sort_keys(&img_2, &f), SortOrder::Ascending);
+        let identity = Matrix::generate(13, 9, |x, y| (x, y));
+        assert_eq!(transfer_map(&map_1, &map_1), identity);
+
+        // Brighter pixels of image 1 receive brighter pixels of image 2.
+        let out = pixel_unsort(&img_2, &transfer_map(&map_1, &map_2));
+        let mut pairs = img_1
+            .pixels()
+            .map(|(x, y, p)| (luma(p), luma(*out.get_pixel(x, y))))
+            .collect::<Vec<_>>();
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for w in pairs.windows(2) {
+            assert!(w[0].1 <= w[1].1 || w[0].0 == w[1].0);
+        }
+    }
+
+    #[test]
+    fn mask_image_test() {
+        // The contaminated mask is the same on every call.
+        let app = App {
+            width: 64,
+            height: 48,
+            contamination: 1.0,
+            ..App::default()
+        };
+        let mask = mask_image(&app);
+        assert_eq!(mask, mask_image(&app));
+        let in_front = mask_fn(&app);
+        assert!(mask
+            .enumerate_pixels()
+            .all(|(x, y, px)| (px[0] == 255) == in_front(x, y)));
+    }
+
+    #[test]
+    fn partial_pixel_map_test() {
+        let img = test_image();
+        let px_map = sort_map_row(
+            &sort_keys(&img, &luma_fn()),
+            SortOrder::Ascending,
+            None,
+            None,
+        );
+        let identity = Matrix::generate(px_map.width, px_map.height, |x, y| (x, y));
+        assert_eq!(partial_pixel_map(&px_map, |_, _| 0.0), identity);
+        assert_eq!(partial_pixel_map(&px_map, |_, _| 1.0), px_map);
+        let half = partial_pixel_map(&px_map, |_, _| 0.5);
+        for y in 0..px_map.height {
+            for x in 0..px_map.width {
+                let (x1, _) = px_map[y][x];
+                let (xh, yh) = half[y][x];
+                assert_eq!(yh, y);
+                assert!(xh.abs_diff(x) <= x1.abs_diff(x) / 2 + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn cell_sort_test() {
+        let img = test_image();
+        let keys = sort_keys(&img, &luma_fn());
+        let asc = SortOrder::Ascending;
+        let identity = Matrix::generate(13, 9, |x, y| (x, y));
+        for cell_layout in [CellLayout::Square, CellLayout::Hex, CellLayout::Voronoi] {
+            for cell_random in [false, true] {
+                let app = App {
+                    cell_layout,
+                    cell_size: 4,
+                    cell_random,
+                    ..App::default()
+                };
+                let cells = cells(&app, 13, 9).unwrap();
+                let c = Some(&cells);
+                let sm = sort_map_column(&keys, asc, c, Some(sort_map_row(&keys, asc, c, None)));
+                let pm = pixel_map_column(&keys, asc, c, Some(pixel_map_row(&keys, asc, c, None)));
+                // Every pixel stays within its cell.
+                for px_map in [&sm, &pm] {
+                    for y in 0..9 {
+                        for x in 0..13 {
+                            let (x1, y1) = px_map[y][x];
+                            assert_eq!(cells.ids[y1][x1], cells.ids[y][x]);
+                        }
+                    }
+                    let mut data = px_map.data.clone();
+                    data.sort_by_key(|p| (p.1, p.0));
+                    assert_eq!(data, identity.data);
+                }
+            }
+        }
+
+        // Runs of a row within a cell are sorted independently.
+        let app = App {
+            cell_layout: CellLayout::Square,
+            cell_size: 4,
+            ..App::default()
+        };
+        let cells = cells(&app, 13, 9).unwrap();
+        let sm = sort_map_row(&keys, asc, Some(&cells), None);
+        for y in 0..9 {
+            for x in 1..13 {
+                let (a, b) = (sm[y][x - 1], sm[y][x]);
+                if x % 4 != 0 {
+                    assert!(keys[a.1][a.0] <= keys[b.1][b.0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn melt_test() {
+        let img = test_image();
+        let keys = sort_keys(&img, &luma_fn());
+        let asc = SortOrder::Ascending;
+        let identity = Matrix::generate(13, 9, |x, y| (x, y));
+        // Nothing is above the threshold, or every run is a single pixel.
+        assert_eq!(
+            melt_map(&keys, asc, MeltDirection::Down, 255.0, |_, _| 4),
+            identity
+        );
+        assert_eq!(
+            melt_map(&keys, asc, MeltDirection::Down, -1.0, |_, _| 1),
+            identity
+        );
+        // With no threshold and long runs a melt is a full sort.
+        let down = melt_map(&keys, asc, MeltDirection::Down, -1.0, |_, _| 100);
+        assert_eq!(down, sort_map_column(&keys, asc, None, None));
+        let left = melt_map(&keys, asc, MeltDirection::Left, -1.0, |_, _| 100);
+        assert_eq!(left, sort_map_row(&keys, SortOrder::Descending, None, None));
+
+        // The threshold runs over the range of the keys, whatever it is.
+        let shifted = Matrix::new(
+            13,
+            9,
+            keys.data.iter().map(|k| k * 300.0 - 9000.0).collect(),
+        );
+        let (lo, hi) = keys
+            .data
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), &k| (lo.min(k), hi.max(k)));
+        assert_eq!(key_threshold(&shifted, 0.0), lo * 300.0 - 9000.0);
+        assert_eq!(key_threshold(&shifted, 1.0), hi * 300.0 - 9000.0);
+        assert_eq!(
+            melt_map(
+                &shifted,
+                asc,
+                MeltDirection::Down,
+                key_threshold(&shifted, 1.0),
+                |_, _| 4
+            ),
+            identity
+        );
+
+        // Runs start at the first pixel above the threshold and move only
+        // pixels within the run.
+        let threshold = 128.0;
+        let right = melt_map(&keys, asc, MeltDirection::Right, threshold, |_, _| 3);
+        for y in 0..9 {
+            let mut x = 0;
+            while x < 13 {
+                if keys[y][x] > threshold {
+                    let end = (x + 3).min(13);
+                    for i in x..end {
+                        assert!((x..end).contains(&right[y][i].0));
+                        assert_eq!(right[y][i].1, y);
+                    }
+                    x = end;
+                } else {
+                    assert_eq!(right[y][x], (x, y));
+                    x += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn non_separable_blend_test() {
+        let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5);
+        let (red, gray) = ([1.0, 0.0, 0.0], [0.5, 0.5, 0.5]);
+        // Gray has no saturation to give red its hue.
+        assert!(close(set_lum(set_sat(red, sat(gray)), lum(gray)), gray));
+        // Raising red to a luminosity of 0.5 clips and keeps the hue.
+        let l = set_lum(red, 0.5);
+        assert!(close(
+            l,
+            [1.0, 0.5 - 0.3 * 0.5 / 0.7, 0.5 - 0.3 * 0.5 / 0.7]
+        ));
+        assert!((lum(l) - 0.5).abs() < 1e-5);
+        assert!(close(set_sat([0.2, 0.6, 0.4], 0.5), [0.0, 0.5, 0.25]));
+
+        let opaque = |c: [f32; 3]| LinSrgba::new(c[0], c[1], c[2], 1.0);
+        let rgb = |c: LinSrgba| [c.red, c.green, c.blue];
+        let (src, dst) = ([0.8, 0.3, 0.1], [0.2, 0.4, 0.9]);
+        let color = non_separable_blend(opaque(src), opaque(dst), BlendMode::Color);
+        assert!((lum(rgb(color)) - lum(dst)).abs() < 1e-5);
+        let lumi = non_separable_blend(opaque(src), opaque(dst), BlendMode::Luminosity);
+        assert!((lum(rgb(lumi)) - lum(src)).abs() < 1e-5);
+        let hue = non_separable_blend(opaque(src), opaque(dst), BlendMode::Hue);
+        assert!((sat(rgb(hue)) - sat(dst)).abs() < 1e-5);
+        let satu = non_separable_blend(opaque(src), opaque(dst), BlendMode::Saturation);
+        assert!((sat(rgb(satu)) - sat(src)).abs() < 1e-5);
+
+        // A transparent source leaves the backdrop.
+        let clear = LinSrgba::new(src[0], src[1], src[2], 0.0);
+        for mode in [BlendMode::Hue, BlendMode::Color] {
+            assert!(close(
+                rgb(non_separable_blend(clear, opaque(dst), mode)),
+                dst
+            ));
+        }
+    }
+
+    // Reference formulas for the separable modes, backdrop first, from the
+    // definitions in the Photoshop and W3C documentation.
+    fn reference(mode: BlendMode, b: f32, s: f32) -> f32 {
+        match mode {
+            BlendMode::LinearBurn => (b + s - 1.0).clamp(0.0, 1.0),
+            BlendMode::LinearDodge => (b + s).clamp(0.0, 1.0),
+            BlendMode::LinearLight => (b + 2.0 * (s - 0.5)).clamp(0.0, 1.0),
+            BlendMode::VividLight if s <= 0.5 => {
+                if b == 1.0 {
+                    1.0
+                } else if s == 0.0 {
+                    0.0
+                } else {
+                    (1.0 - (1.0 - b) / (2.0 * s)).clamp(0.0, 1.0)
+                }
+            }
+            BlendMode::VividLight => {
+                if b == 0.0 {
+                    0.0
+                } else if s == 1.0 {
+                    1.0
+                } else {
+                    (b / (2.0 * (1.0 - s))).clamp(0.0, 1.0)
+                }
+            }
+            BlendMode::PinLight if s <= 0.5 => b.min(2.0 * s),
+            BlendMode::PinLight => b.max(2.0 * (s - 0.5)),
+            BlendMode::HardMix => (b + s).floor().min(1.0),
+            BlendMode::Subtract => (b - s).clamp(0.0, 1.0),
+            BlendMode::Divide if s == 0.0 => b.ceil(),
+            BlendMode::Divide => (b / s).clamp(0.0, 1.0),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn separable_blend_test() {
+        let levels = [0u8, 1, 32, 64, 100, 128, 160, 191, 230, 254, 255];
+        let lin = |v: u8| Srgb::new(v, v, v).into_linear::<f32>().red;
+        let modes = [
+            BlendMode::LinearBurn,
+            BlendMode::LinearDodge,
+            BlendMode::LinearLight,
+            BlendMode::VividLight,
+            BlendMode::PinLight,
+            BlendMode::HardMix,
+            BlendMode::Subtract,
+            BlendMode::Divide,
+        ];
+        for mode in modes {
+            for &v1 in &levels {
+                for &v2 in &levels {
+                    let c1 = Rgba([v1, v2, v1, 255]);
+                    let c2 = Rgba([v2, v1, 255 - v2, 255]);
+                    let out = blend(c1, c2, mode, 255, 255, BlendSpace::Linear);
+                    for i in 0..3 {
+                        let expected = reference(mode, lin(c2[i]), lin(c1[i]));
+                        let expected = Srgb::<f32>::from_linear(LinSrgb::new(expected, 0.0, 0.0));
+                        let expected = (expected.red * 255.0).round() as i32;
+                        assert!(
+                            (out[i] as i32 - expected).abs() <= 1,
+                            "{:?} {:?} {:?}: {} != {}",
+                            mode,
+                            c1,
+                            c2,
+                            out[i],
+                            expected
+                        );
+                    }
+                    assert_eq!(out[3], 255);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn darker_lighter_color_test() {
+        let (c1, c2) = (Rgba([250, 20, 20, 255]), Rgba([90, 90, 90, 255]));
+        // In linear light the red is lighter, and the whole color is kept.
+        assert_eq!(
+            blend(c1, c2, BlendMode::DarkerColor, 255, 255, BlendSpace::Linear),
+            Rgba([90, 90, 90, 255])
+        );
+        assert_eq!(
+            blend(
+                c1,
+                c2,
+                BlendMode::LighterColor,
+                255,
+                255,
+                BlendSpace::Linear
+            ),
+            Rgba([250, 20, 20, 255])
+        );
+        assert_eq!(
+            blend(c2, c1, BlendMode::DarkerColor, 255, 255, BlendSpace::Linear),
+            Rgba([90, 90, 90, 255])
+        );
+    }
+
+    #[test]
+    fn blend_space_test() {
+        let (c1, c2) = (Rgba([128, 200, 40, 255]), Rgba([128, 60, 220, 255]));
+        // Multiply in sRGB works on the encoded values like other tools.
+        let srgb = blend(c1, c2, BlendMode::Multiply, 255, 255, BlendSpace::Srgb);
+        for i in 0..3 {
+            let expected = c1[i] as f32 * c2[i] as f32 / 255.0;
+            assert!((srgb[i] as f32 - expected).abs() <= 1.0);
+        }
+        let linear = blend(c1, c2, BlendMode::Multiply, 255, 255, BlendSpace::Linear);
+        assert!(linear[0] < srgb[0]);
+
+        // An opaque source over anything round trips through every space.
+        for space in [BlendSpace::Linear, BlendSpace::Srgb, BlendSpace::Oklab] {
+            let out = blend(c1, c2, BlendMode::Normal, 255, 255, space);
+            for i in 0..4 {
+                assert!(out[i].abs_diff(c1[i]) <= 1, "{:?} {:?}", space, out);
+            }
+        }
+        // Half way between two grays keeps the Oklab lightness half way.
+        let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        let mid = blend(white, black, BlendMode::Normal, 128, 255, BlendSpace::Oklab);
+        let l = Oklab::from_color(Srgb::new(mid[0], mid[1], mid[2]).into_format::<f32>()).l;
+        assert!((l - 0.5).abs() < 0.01);
+        assert_eq!((mid[0], mid[1]), (mid[1], mid[2]));
+    }
+
+    #[test]
+    fn opacity_map_test() {
+        let app = App {
+            width: 20,
+            height: 10,
+            ..App::default()
+        };
+        let map = |kind, angle, invert| OpacityMap {
+            kind,
+            angle,
+            invert,
+            ..OpacityMap::default()
+        };
+        let constant = opacity_fn(&app, &OpacityMap::default(), 200, None, 51);
+        assert_eq!((constant(0, 0), constant(19, 9)), (200, 200));
+
+        // A linear gradient is opaque at its end and clear at its start.
+        let linear = opacity_fn(&app, &map(OpacityKind::Linear, 0.0, false), 255, None, 51);
+        assert!(linear(0, 5) < 10 && linear(19, 5) > 245);
+        assert!(linear(3, 0) < linear(4, 0));
+        assert_eq!(linear(7, 0), linear(7, 9));
+        let down = opacity_fn(&app, &map(OpacityKind::Linear, 90.0, true), 255, None, 51);
+        assert!(down(10, 0) > 240 && down(10, 9) < 15);
+
+        // A radial gradient is opaque at the center.
+        let radial = opacity_fn(&app, &map(OpacityKind::Radial, 0.0, false), 100, None, 51);
+        assert!(radial(10, 5) > 90 && radial(0, 0) < 10);
+
+        // A mask image is stretched over the output, white is opaque.
+        let mask = GrayImage::from_fn(2, 1, |x, _| Luma([if x == 0 { 0 } else { 255 }]));
+        let mask_map = map(OpacityKind::Image, 0.0, false);
+        let masked = opacity_fn(&app, &mask_map, 255, Some(&mask), 51);
+        assert!(masked(0, 5) < 10 && masked(19, 5) > 245);
+        let missing = opacity_fn(&app, &map(OpacityKind::Image, 0.0, true), 255, None, 51);
+        assert_eq!(missing(0, 0), 255);
+    }
+
+    #[test]
+    fn pixel_sort_column_test() {
+        // A position dependent key checks that columns see the right y.
+        let img = test_image();
+        let f: SortFn = Arc::new(|_, y, c| luma(c) + 8.0 * y as f32);
+        let sorted = pixel_sort_column(&img, &f, SortOrder::Descending);
+        for x in 0..img.width() {
+            let mut column = (0..img.height())
+                .map(|y| (f(x, y, img.get_pixel(x, y)), img.get_pixel(x, y)))
+                .collect::<Vec<_>>();
+            column.sort_by(|a, b| b.0.total_cmp(&a.0));
+            for (y, (_, p)) in column.into_iter().enumerate() {
+                assert_eq!(*sorted.get_pixel(x, y as u32), p);
+            }
         }
     }
-    out_image
 }
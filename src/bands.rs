@@ -0,0 +1,289 @@
+//! Splitting images into frequency bands, to combine the broad forms of one
+//! image with the fine detail of another.
+
+use crate::matrix::Matrix;
+use image::{DynamicImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+// An image or one band of it, with channels from 0 to 1 (bands can be
+// negative).
+type Layer = Matrix<[f32; 4]>;
+
+// The 5 tap binomial filter used to reduce each pyramid level.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+/// The image softened by the fast box blur approximation of a Gaussian with
+/// standard deviation `sigma`, or the image itself when `sigma` is 0.
+pub fn low_pass(img: &DynamicImage, sigma: f32) -> RgbaImage {
+    if sigma > 0.0 {
+        img.fast_blur(sigma).to_rgba8()
+    } else {
+        img.to_rgba8()
+    }
+}
+
+/// A hybrid image, the frequencies of `low` below the cutoff with those of
+/// `high` above it scaled by `detail`. The bands are split by a Gaussian with
+/// the cutoff as its standard deviation.
+pub fn hybrid(low: &RgbaImage, high: &RgbaImage, cutoff: f32, detail: f32) -> RgbaImage {
+    let high = to_layer(high);
+    let base = gaussian_blur(&to_layer(low), cutoff);
+    let high_blur = gaussian_blur(&high, cutoff);
+    let img = par_generate(base.width, base.height, |x, y| {
+        let (b, h, hb) = (base[y][x], high[y][x], high_blur[y][x]);
+        let mut c: [f32; 4] = std::array::from_fn(|i| b[i] + detail * (h[i] - hb[i]));
+        c[3] = b[3];
+        c
+    });
+    from_layer(&img)
+}
+
+// A Gaussian blur with standard deviation `sigma`, along the rows and then
+// the columns, with the kernel cut off at 3 sigma and the edges clamped.
+fn gaussian_blur(layer: &Layer, sigma: f32) -> Layer {
+    let (w, h) = (layer.width, layer.height);
+    if sigma <= 0.0 {
+        return par_generate(w, h, |x, y| layer[y][x]);
+    }
+    let radius = (3.0 * sigma).ceil() as usize;
+    let weights: Vec<f32> = (0..=2 * radius)
+        .map(|k| {
+            let d = k as f32 - radius as f32;
+            (-d * d / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let total: f32 = weights.iter().sum();
+    let weights: Vec<f32> = weights.iter().map(|wt| wt / total).collect();
+    let tap = |i: usize, k: usize, n: usize| (i + k).saturating_sub(radius).min(n - 1);
+    let rows = par_generate(w, h, |x, y| {
+        let mut c = [0.0; 4];
+        for (k, wt) in weights.iter().enumerate() {
+            let p = layer[y][tap(x, k, w)];
+            (0..4).for_each(|i| c[i] += wt * p[i]);
+        }
+        c
+    });
+    par_generate(w, h, |x, y| {
+        let mut c = [0.0; 4];
+        for (k, wt) in weights.iter().enumerate() {
+            let p = rows[tap(y, k, h)][x];
+            (0..4).for_each(|i| c[i] += wt * p[i]);
+        }
+        c
+    })
+}
+
+/// Blend two images across the bands of their Laplacian pyramids, each band
+/// mixed by the mask blurred to the same scale, so that coarse differences
+/// in color and exposure fade over wide transitions while edges stay sharp.
+/// The mask is white where image 1 is in front.
+pub fn multiband_blend(
+    img_1: &RgbaImage,
+    img_2: &RgbaImage,
+    mask: &RgbaImage,
+    levels: usize,
+) -> RgbaImage {
+    let pyr_1 = laplacian_pyramid(to_layer(img_1), levels);
+    let pyr_2 = laplacian_pyramid(to_layer(img_2), levels);
+    let masks = gaussian_pyramid(to_layer(mask), levels);
+    let blended = pyr_1
+        .iter()
+        .zip(&pyr_2)
+        .zip(&masks)
+        .map(|((l1, l2), m)| {
+            par_generate(l1.width, l1.height, |x, y| {
+                let (a, b, t) = (l1[y][x], l2[y][x], m[y][x][0]);
+                std::array::from_fn(|i| a[i] * t + b[i] * (1.0 - t))
+            })
+        })
+        .collect();
+    from_layer(&collapse(blended))
+}
+
+fn to_layer(img: &RgbaImage) -> Layer {
+    par_generate(img.width() as usize, img.height() as usize, |x, y| {
+        img.get_pixel(x as u32, y as u32)
+            .0
+            .map(|v| v as f32 / 255.0)
+    })
+}
+
+fn from_layer(layer: &Layer) -> RgbaImage {
+    RgbaImage::from_fn(layer.width as u32, layer.height as u32, |x, y| {
+        Rgba(layer[y as usize][x as usize].map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8))
+    })
+}
+
+fn par_generate<F>(width: usize, height: usize, f: F) -> Layer
+where
+    F: Fn(usize, usize) -> [f32; 4] + Sync,
+{
+    let mut data = Vec::with_capacity(width * height);
+    (0..width * height)
+        .into_par_iter()
+        .map(|i| f(i % width, i / width))
+        .collect_into_vec(&mut data);
+    Matrix::new(width, height, data)
+}
+
+// Blur with the binomial filter and drop every other row and column.
+fn reduce(layer: &Layer) -> Layer {
+    let (w, h) = (layer.width, layer.height);
+    let (w2, h2) = (w.div_ceil(2), h.div_ceil(2));
+    let tap = |i: usize, k: usize, n: usize| (2 * i + k).saturating_sub(2).min(n - 1);
+    let rows = par_generate(w2, h, |x, y| {
+        let mut c = [0.0; 4];
+        for (k, wt) in KERNEL.iter().enumerate() {
+            let p = layer[y][tap(x, k, w)];
+            (0..4).for_each(|i| c[i] += wt * p[i]);
+        }
+        c
+    });
+    par_generate(w2, h2, |x, y| {
+        let mut c = [0.0; 4];
+        for (k, wt) in KERNEL.iter().enumerate() {
+            let p = rows[tap(y, k, h)][x];
+            (0..4).for_each(|i| c[i] += wt * p[i]);
+        }
+        c
+    })
+}
+
+// Bilinearly upsample a reduced layer back to the size of the level above.
+fn expand(layer: &Layer, width: usize, height: usize) -> Layer {
+    let (w, h) = (layer.width, layer.height);
+    par_generate(width, height, |x, y| {
+        let fx = ((x as f32 + 0.5) / 2.0 - 0.5).clamp(0.0, (w - 1) as f32);
+        let fy = ((y as f32 + 0.5) / 2.0 - 0.5).clamp(0.0, (h - 1) as f32);
+        let (x0, y0) = (fx as usize, fy as usize);
+        let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+        let (tx, ty) = (fx - x0 as f32, fy - y0 as f32);
+        let (a, b, c, d) = (layer[y0][x0], layer[y0][x1], layer[y1][x0], layer[y1][x1]);
+        std::array::from_fn(|i| {
+            let top = a[i] + (b[i] - a[i]) * tx;
+            let bottom = c[i] + (d[i] - c[i]) * tx;
+            top + (bottom - top) * ty
+        })
+    })
+}
+
+// The layer followed by successively reduced copies, stopping early once a
+// level is a single pixel wide or high.
+fn gaussian_pyramid(layer: Layer, levels: usize) -> Vec<Layer> {
+    let mut pyramid = vec![layer];
+    while pyramid.len() < levels.max(1) {
+        let last = &pyramid[pyramid.len() - 1];
+        if last.width < 2 || last.height < 2 {
+            break;
+        }
+        let next = reduce(last);
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+// Each level of the Gaussian pyramid less the expanded level below it, with
+// the smallest level kept as is.
+fn laplacian_pyramid(layer: Layer, levels: usize) -> Vec<Layer> {
+    let gaussian = gaussian_pyramid(layer, levels);
+    let mut pyramid: Vec<Layer> = gaussian
+        .windows(2)
+        .map(|pair| {
+            let (g, next) = (&pair[0], &pair[1]);
+            let up = expand(next, g.width, g.height);
+            par_generate(g.width, g.height, |x, y| {
+                let (a, b) = (g[y][x], up[y][x]);
+                std::array::from_fn(|i| a[i] - b[i])
+            })
+        })
+        .collect();
+    pyramid.extend(gaussian.into_iter().last());
+    pyramid
+}
+
+// Add the bands of a Laplacian pyramid back up into an image.
+fn collapse(pyramid: Vec<Layer>) -> Layer {
+    let mut levels = pyramid.into_iter().rev();
+    let mut img = levels.next().expect("a pyramid has at least one level");
+    for band in levels {
+        let up = expand(&img, band.width, band.height);
+        img = par_generate(band.width, band.height, |x, y| {
+            let (a, b) = (band[y][x], up[y][x]);
+            std::array::from_fn(|i| a[i] + b[i])
+        });
+    }
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> RgbaImage {
+        let mut rng = fastrand::Rng::with_seed(11);
+        RgbaImage::from_fn(16, 12, |_, _| {
+            Rgba([rng.u8(..), rng.u8(..), rng.u8(..), 255])
+        })
+    }
+
+    #[test]
+    fn multiband_blend_test() {
+        let img = test_image();
+        // The bands of a pyramid add back up to the image.
+        let layer = to_layer(&img);
+        assert_eq!(from_layer(&collapse(laplacian_pyramid(layer, 5))), img);
+
+        let white = RgbaImage::from_pixel(16, 12, Rgba([255, 255, 255, 255]));
+        let black = RgbaImage::from_pixel(16, 12, Rgba([0, 0, 0, 255]));
+        assert_eq!(multiband_blend(&img, &black, &white, 5), img);
+        assert_eq!(multiband_blend(&img, &black, &black, 5), black);
+
+        // A hard mask becomes a smooth transition between flat images.
+        let half = RgbaImage::from_fn(
+            16,
+            12,
+            |x, _| if x < 8 { white[(0, 0)] } else { black[(0, 0)] },
+        );
+        let out = multiband_blend(&black, &white, &half, 3);
+        let row: Vec<u8> = (0..16).map(|x| out.get_pixel(x, 6)[0]).collect();
+        assert!(row.windows(2).all(|p| p[0] <= p[1]));
+        assert!(row[7] > 0 && row[8] < 255);
+        assert!(row[0] < 64 && row[15] > 191);
+    }
+
+    #[test]
+    fn gaussian_blur_test() {
+        // An impulse spreads into a Gaussian that keeps its total.
+        let sigma = 2.0;
+        let mut layer = Matrix::new(31, 31, vec![[0.0; 4]; 31 * 31]);
+        layer[15][15] = [1.0; 4];
+        let blurred = gaussian_blur(&layer, sigma);
+        let total: f32 = blurred.data.iter().map(|c| c[0]).sum();
+        assert!((total - 1.0).abs() < 1e-4);
+        let ratio = blurred[15][15 + 2][0] / blurred[15][15][0];
+        assert!((ratio - (-4.0f32 / (2.0 * sigma * sigma)).exp()).abs() < 1e-3);
+        assert_eq!(blurred[15][13][0], blurred[15][17][0]);
+        assert_eq!(blurred[13][15][0], blurred[15][13][0]);
+    }
+
+    #[test]
+    fn hybrid_test() {
+        let img = test_image();
+        let other = RgbaImage::from_fn(16, 12, |x, y| {
+            Rgba([(x * 15) as u8, (y * 20) as u8, 90, 255])
+        });
+        // Without detail only the low frequencies of the low image remain.
+        let low = from_layer(&gaussian_blur(&to_layer(&other), 3.0));
+        assert_eq!(hybrid(&other, &img, 3.0, 0.0), low);
+        // No cutoff leaves the low image as it is.
+        assert_eq!(hybrid(&other, &img, 0.0, 1.0), other);
+
+        // The two bands of one image add back up to the image.
+        let same = hybrid(&img, &img, 3.0, 1.0);
+        for (a, b) in same.pixels().zip(img.pixels()) {
+            for i in 0..4 {
+                assert!(a[i].abs_diff(b[i]) <= 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Cells that pixels are sorted within, so that a sort only rearranges pixels
+//! locally and the composition of the image is preserved.
+
+use crate::core::{App, CellLayout, SortOrder};
+use crate::matrix::Matrix;
+use rayon::prelude::*;
+
+pub struct Cells {
+    // The cell each pixel belongs to. Every cell is convex so each row and
+    // column crosses it in a single run.
+    pub ids: Matrix<u32>,
+    // For each cell whether it is sorted along rows and its order, when the
+    // cells are randomized.
+    pub random: Option<Vec<(bool, SortOrder)>>,
+}
+
+impl Cells {
+    /// Does a pass along rows (or columns) sort this cell?
+    pub fn sorts(&self, cell: usize, along_rows: bool) -> bool {
+        match &self.random {
+            Some(r) => r[cell].0 == along_rows,
+            None => true,
+        }
+    }
+
+    /// The order of the cell, or the pass's order if not randomized.
+    pub fn order(&self, cell: usize, order: SortOrder) -> SortOrder {
+        match &self.random {
+            Some(r) => r[cell].1,
+            None => order,
+        }
+    }
+}
+
+/// The cells for the app's layout at the given size, if any.
+pub fn cells(app: &App, width: usize, height: usize) -> Option<Cells> {
+    let size = app.cell_size.max(2) as f32;
+    let (ids, count) = match app.cell_layout {
+        CellLayout::Off => return None,
+        CellLayout::Square => square_cells(width, height, size),
+        CellLayout::Hex => hex_cells(width, height, size),
+        CellLayout::Voronoi => voronoi_cells(width, height, size),
+    };
+    let random = app.cell_random.then(|| {
+        let mut rng = fastrand::Rng::with_seed(53);
+        (0..count)
+            .map(|_| {
+                let order = if rng.bool() {
+                    SortOrder::Ascending
+                } else {
+                    SortOrder::Descending
+                };
+                (rng.bool(), order)
+            })
+            .collect()
+    });
+    Some(Cells { ids, random })
+}
+
+// Assign each pixel to the nearest center, returning the cell ids. `near`
+// lists the candidate centers for a pixel.
+fn nearest<F>(width: usize, height: usize, centers: &[(f32, f32)], near: F) -> Matrix<u32>
+where
+    F: Fn(f32, f32) -> Vec<usize> + Sync,
+{
+    let data = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = ((i % width) as f32 + 0.5, (i / width) as f32 + 0.5);
+            let mut best = (f32::MAX, 0);
+            for c in near(x, y) {
+                let (cx, cy) = centers[c];
+                let d = (cx - x) * (cx - x) + (cy - y) * (cy - y);
+                if d < best.0 {
+                    best = (d, c);
+                }
+            }
+            best.1 as u32
+        })
+        .collect();
+    Matrix::new(width, height, data)
+}
+
+// Square blocks of the given size.
+fn square_cells(width: usize, height: usize, size: f32) -> (Matrix<u32>, usize) {
+    let s = size as usize;
+    let cols = width.div_ceil(s);
+    let ids = Matrix::generate(width, height, |x, y| ((y / s) * cols + x / s) as u32);
+    (ids, cols * height.div_ceil(s))
+}
+
+// Hexagons with the given distance between neighboring centers.
+fn hex_cells(width: usize, height: usize, size: f32) -> (Matrix<u32>, usize) {
+    let dy = size * 3f32.sqrt() / 2.0;
+    let cols = (width as f32 / size) as usize + 2;
+    let rows = (height as f32 / dy) as usize + 2;
+    let centers = (0..rows * cols)
+        .map(|i| {
+            let (c, r) = (i % cols, i / cols);
+            let shift = if r % 2 == 1 { size / 2.0 } else { 0.0 };
+            (c as f32 * size + shift, r as f32 * dy)
+        })
+        .collect::<Vec<_>>();
+    let ids = nearest(width, height, &centers, |x, y| {
+        let (r, c) = ((y / dy) as usize, (x / size) as usize);
+        let mut near = Vec::with_capacity(9);
+        for r in r.saturating_sub(1)..(r + 2).min(rows) {
+            for c in c.saturating_sub(1)..(c + 2).min(cols) {
+                near.push(r * cols + c);
+            }
+        }
+        near
+    });
+    (ids, centers.len())
+}
+
+// Voronoi cells of one randomly placed center per block of the given size.
+fn voronoi_cells(width: usize, height: usize, size: f32) -> (Matrix<u32>, usize) {
+    let cols = (width as f32 / size).ceil() as usize;
+    let rows = (height as f32 / size).ceil() as usize;
+    let mut rng = fastrand::Rng::with_seed(47);
+    let centers = (0..rows * cols)
+        .map(|i| {
+            let (c, r) = (i % cols, i / cols);
+            ((c as f32 + rng.f32()) * size, (r as f32 + rng.f32()) * size)
+        })
+        .collect::<Vec<_>>();
+    // A center more than two blocks away is never the nearest.
+    let ids = nearest(width, height, &centers, |x, y| {
+        let (r, c) = ((y / size) as usize, (x / size) as usize);
+        let mut near = Vec::with_capacity(25);
+        for r in r.saturating_sub(2)..(r + 3).min(rows) {
+            for c in c.saturating_sub(2)..(c + 3).min(cols) {
+                near.push(r * cols + c);
+            }
+        }
+        near
+    });
+    (ids, centers.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each row and column must cross a cell in a single run.
+    fn assert_convex(ids: &Matrix<u32>) {
+        for lines in [ids, &ids.transpose()] {
+            for y in 0..lines.height {
+                let mut seen = std::collections::HashSet::new();
+                for run in lines[y].chunk_by(|a, b| a == b) {
+                    assert!(seen.insert(run[0]), "cell {} is split", run[0]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_test() {
+        let (w, h) = (97, 61);
+        for (ids, count) in [
+            square_cells(w, h, 16.0),
+            hex_cells(w, h, 16.0),
+            voronoi_cells(w, h, 16.0),
+        ] {
+            assert_eq!((ids.width, ids.height), (w, h));
+            assert!(ids.data.iter().all(|&c| (c as usize) < count));
+            assert_convex(&ids);
+        }
+        let (ids, count) = square_cells(w, h, 16.0);
+        assert_eq!(count, 7 * 4);
+        assert_eq!(ids[17][33], 7 + 2);
+    }
+}
@@ -1,11 +1,12 @@
 use egui::{ColorImage, TextureHandle};
 use image::{
     imageops::{self, FilterType},
-    RgbaImage,
+    GrayImage, RgbaImage,
 };
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 
+use crate::dither::Quantizer;
 use crate::matrix::Matrix;
 use serde::{Deserialize, Serialize};
 use std::ops::Neg;
@@ -35,9 +36,20 @@ pub enum Combine {
     Blend,
     Divide,
     Mix,
+    Reinhard,
+    Histogram,
+    Palette,
     Warp,
     Unsort,
     Sort,
+    CrossSort,
+    Transfer,
+    Melt,
+    Remap,
+    Key,
+    Matte,
+    Hybrid,
+    Spectrum,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
@@ -60,6 +72,104 @@ pub enum BlendMode {
     SoftLight,
     Difference,
     Exclusion,
+    LinearBurn,
+    LinearDodge,
+    LinearLight,
+    VividLight,
+    PinLight,
+    HardMix,
+    Subtract,
+    Divide,
+    DarkerColor,
+    LighterColor,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum OpacityKind {
+    Constant,
+    Linear,
+    Radial,
+    Noise,
+    Image,
+}
+
+// Varies an image's opacity over the output, scaling its opacity setting.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+pub struct OpacityMap {
+    pub kind: OpacityKind,
+    // Direction of the linear gradient in degrees, opaque at its end.
+    pub angle: f32,
+    pub scale: f32,
+    pub invert: bool,
+    // A grayscale image, white is opaque.
+    pub path: Option<String>,
+}
+
+impl Default for OpacityMap {
+    fn default() -> Self {
+        Self {
+            kind: OpacityKind::Constant,
+            angle: 0.0,
+            scale: 4.0,
+            invert: false,
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum BlendSpace {
+    Linear,
+    Srgb,
+    Oklab,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum KeyKind {
+    Luma,
+    Chroma,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum HistogramSpace {
+    Rgb,
+    Luma,
+    Oklch,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum PaletteMethod {
+    KMeans,
+    MedianCut,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum PaletteMapping {
+    Nearest,
+    Rank,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum QuantizePalette {
+    Levels,
+    Image2,
+    Mono,
+    Cmyk,
+    Riso,
+    GameBoy,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum DitherKind {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Bayer,
+    BlueNoise,
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
@@ -70,6 +180,22 @@ pub enum SortBy {
     RowCol,
 }
 
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum MeltDirection {
+    Down,
+    Up,
+    Left,
+    Right,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum CellLayout {
+    Off,
+    Square,
+    Hex,
+    Voronoi,
+}
+
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
 pub enum SortKey {
     Lightness,
@@ -84,11 +210,66 @@ pub enum SortKey {
     HueSat,
     LumaSat,
     Chroma,
+    OklabL,
+    OklchChroma,
+    OklchHue,
+    LabL,
+    LabA,
+    LabB,
+    HsvValue,
+    Luminance,
+    Distance,
+    Custom,
+}
+
+// The features a custom sort key can be built from.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub enum SortFeature {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Luma,
+    Hue,
+    Saturation,
+    Lightness,
+    OklchL,
+    OklchChroma,
+    OklchHue,
+    X,
+    Y,
+    Noise,
+}
+
+// One weighted term of a custom sort key, `levels` > 1 quantizes the feature.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy)]
+pub struct SortTerm {
+    pub feature: SortFeature,
+    pub weight: f32,
+    pub levels: u32,
 }
 
 // Used to store the location of each pixel in the sort image.
 pub type ImgGrid = Matrix<(usize, usize)>;
 
+// The sort key of each pixel, computed once per sort.
+pub type KeyGrid = Matrix<f32>;
+
+// The output of a drawing.
+pub struct Render {
+    pub img: RgbaImage,
+    // The pixel map used by the sorting modes.
+    pub px_map: Option<ImgGrid>,
+    // The image the pixel map was applied to.
+    pub source: Option<RgbaImage>,
+    // The extra images with the pixel map applied, one per input.
+    pub extras: Vec<RgbaImage>,
+    // The palette extracted from image 2, darkest first.
+    pub palette: Vec<[u8; 3]>,
+    // The colors the output was quantized to.
+    pub quantizer: Option<Quantizer>,
+}
+
 // Sort by increasing or decreasing direction of the sort function.
 #[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, Clone, Copy)]
 pub enum SortOrder {
@@ -97,10 +278,10 @@ pub enum SortOrder {
 }
 
 impl SortOrder {
-    pub fn dir(self) -> i16 {
+    pub fn dir(self) -> f32 {
         match self {
-            SortOrder::Ascending => 1,
-            SortOrder::Descending => -1,
+            SortOrder::Ascending => 1.0,
+            SortOrder::Descending => -1.0,
         }
     }
 }
@@ -127,6 +308,8 @@ pub struct App {
     pub hue_rotation_2: i32,
     pub opacity_1: u8,
     pub opacity_2: u8,
+    pub opacity_map_1: OpacityMap,
+    pub opacity_map_2: OpacityMap,
     pub width: u32,
     pub height: u32,
     pub spacing: f32,
@@ -138,7 +321,10 @@ pub struct App {
     pub contamination: f32,
     pub octaves: usize,
     pub cutoff: f32,
+    pub multiband: bool,
+    pub multiband_levels: u32,
     pub mode: BlendMode,
+    pub blend_space: BlendSpace,
     pub combine: Combine,
     pub screen: bool,
     pub angle_scale: f32,
@@ -146,11 +332,87 @@ pub struct App {
     pub radius_scale: f32,
     pub radius_factor: f32,
     pub sort_key: SortKey,
+    pub sort_reference: [u8; 3],
+    pub custom_terms: Vec<SortTerm>,
     pub sort_by: SortBy,
     pub row_sort_order: SortOrder,
     pub col_sort_order: SortOrder,
+    pub cell_layout: CellLayout,
+    pub cell_size: u32,
+    pub cell_random: bool,
+    pub melt_direction: MeltDirection,
+    pub melt_order: SortOrder,
+    pub melt_threshold: f32,
+    pub melt_length: f32,
+    pub melt_scale: f32,
+    pub transfer_whole: bool,
+    pub sort_strength: f32,
+    pub strength_noise: f32,
+    pub strength_scale: f32,
     pub grain_scale: f32,
     pub grain_factor: f32,
+    pub map_path: Option<String>,
+    pub extra_paths: Vec<String>,
+    pub sort_mask: bool,
+    pub anim_frames: u32,
+    pub anim_delay: u32,
+    pub key_kind: KeyKind,
+    pub key_color: [u8; 3],
+    pub key_threshold: f32,
+    pub key_below: bool,
+    pub key_tolerance: f32,
+    pub key_softness: f32,
+    pub key_spill: f32,
+    pub matte_threshold: f32,
+    pub matte_softness: f32,
+    pub matte_blur: f32,
+    pub matte_swap: bool,
+    pub matte_show: bool,
+    pub hybrid_cutoff: f32,
+    pub hybrid_detail: f32,
+    pub hybrid_swap: bool,
+    pub spectrum_mix: f32,
+    pub spectrum_luma: bool,
+    pub spectrum_swap: bool,
+    pub reinhard_strength: f32,
+    pub reinhard_luma: bool,
+    pub histogram_space: HistogramSpace,
+    pub histogram_strength: f32,
+    pub palette_size: u32,
+    pub palette_method: PaletteMethod,
+    pub palette_mapping: PaletteMapping,
+    pub quantize: bool,
+    pub quantize_palette: QuantizePalette,
+    pub quantize_levels: u32,
+    pub quantize_colors: u32,
+    pub dither_kind: DitherKind,
+    pub dither_amount: f32,
+
+    // The pixel map of the last render.
+    #[serde(skip)]
+    pub pixel_map: Option<Arc<ImgGrid>>,
+
+    // The pixel map read from `map_path`, which Remap resamples.
+    #[serde(skip)]
+    pub loaded_map: Option<Arc<ImgGrid>>,
+
+    #[serde(skip)]
+    pub opacity_img_1: Option<GrayImage>,
+
+    #[serde(skip)]
+    pub opacity_img_2: Option<GrayImage>,
+
+    #[serde(skip)]
+    pub map_source: Option<RgbaImage>,
+
+    #[serde(skip)]
+    pub extra_imgs: Vec<RgbaImage>,
+
+    #[serde(skip)]
+    pub extra_outputs: Vec<RgbaImage>,
+
+    #[serde(skip)]
+    pub palette: Vec<[u8; 3]>,
 
     #[serde(skip)]
     pub texture: Option<TextureHandle>,
@@ -174,7 +436,14 @@ pub struct App {
     pub drawing_in_progress: bool,
 
     #[serde(skip)]
-    pub draw_receiver: Option<Receiver<(TextureHandle, RgbaImage)>>,
+    pub draw_receiver: Option<Receiver<(TextureHandle, Render)>>,
+
+    #[serde(skip)]
+    pub export_receiver: Option<Receiver<Result<(), String>>>,
+
+    // The quantizer of the last render, used again on exported frames.
+    #[serde(skip)]
+    pub quantizer: Option<Quantizer>,
 
     #[serde(skip)]
     pub status_message: String,
@@ -203,7 +472,10 @@ impl Default for App {
             contamination: 0.25,
             octaves: 2,
             cutoff: 0.0,
+            multiband: false,
+            multiband_levels: 6,
             mode: BlendMode::Screen,
+            blend_space: BlendSpace::Linear,
             combine: Combine::Blend,
             screen: true,
             angle_scale: 5.0,
@@ -211,11 +483,78 @@ impl Default for App {
             radius_scale: 5.0,
             radius_factor: 1000.0,
             sort_key: SortKey::Lightness,
+            sort_reference: [255, 0, 0],
+            custom_terms: vec![
+                SortTerm {
+                    feature: SortFeature::Luma,
+                    weight: 0.7,
+                    levels: 0,
+                },
+                SortTerm {
+                    feature: SortFeature::Hue,
+                    weight: 0.3,
+                    levels: 0,
+                },
+            ],
             sort_by: SortBy::Row,
             row_sort_order: SortOrder::Ascending,
             col_sort_order: SortOrder::Ascending,
+            cell_layout: CellLayout::Off,
+            cell_size: 64,
+            cell_random: false,
+            melt_direction: MeltDirection::Down,
+            melt_order: SortOrder::Ascending,
+            melt_threshold: 0.5,
+            melt_length: 200.0,
+            melt_scale: 4.0,
+            transfer_whole: false,
+            sort_strength: 1.0,
+            strength_noise: 0.0,
+            strength_scale: 4.0,
             grain_factor: 10.0,
             grain_scale: 0.35,
+            map_path: None,
+            extra_paths: Vec::new(),
+            sort_mask: false,
+            anim_frames: 30,
+            anim_delay: 66,
+            key_kind: KeyKind::Chroma,
+            key_color: [0, 255, 0],
+            key_threshold: 128.0,
+            key_below: false,
+            key_tolerance: 30.0,
+            key_softness: 20.0,
+            key_spill: 0.5,
+            matte_threshold: 20.0,
+            matte_softness: 10.0,
+            matte_blur: 4.0,
+            matte_swap: false,
+            matte_show: false,
+            hybrid_cutoff: 8.0,
+            hybrid_detail: 1.0,
+            hybrid_swap: false,
+            spectrum_mix: 1.0,
+            spectrum_luma: false,
+            spectrum_swap: false,
+            reinhard_strength: 1.0,
+            reinhard_luma: false,
+            histogram_space: HistogramSpace::Rgb,
+            histogram_strength: 1.0,
+            palette_size: 8,
+            palette_method: PaletteMethod::KMeans,
+            palette_mapping: PaletteMapping::Nearest,
+            quantize: false,
+            quantize_palette: QuantizePalette::Levels,
+            quantize_levels: 2,
+            quantize_colors: 8,
+            dither_kind: DitherKind::FloydSteinberg,
+            dither_amount: 1.0,
+            pixel_map: None,
+            loaded_map: None,
+            map_source: None,
+            extra_imgs: Vec::new(),
+            extra_outputs: Vec::new(),
+            palette: Vec::new(),
             texture: None,
             thumbnail_1: None,
             thumbnail_2: None,
@@ -224,8 +563,14 @@ impl Default for App {
             img: RgbaImage::new(1, 1),
             opacity_1: 255,
             opacity_2: 255,
+            opacity_map_1: OpacityMap::default(),
+            opacity_map_2: OpacityMap::default(),
+            opacity_img_1: None,
+            opacity_img_2: None,
             drawing_in_progress: false,
             draw_receiver: None,
+            export_receiver: None,
+            quantizer: None,
             status_message: String::new(),
             status_message_arc: None,
         }
@@ -243,6 +588,10 @@ impl Clone for App {
             hue_rotation_2: self.hue_rotation_2,
             opacity_1: self.opacity_1,
             opacity_2: self.opacity_2,
+            opacity_map_1: self.opacity_map_1.clone(),
+            opacity_map_2: self.opacity_map_2.clone(),
+            opacity_img_1: self.opacity_img_1.clone(),
+            opacity_img_2: self.opacity_img_2.clone(),
             width: self.width,
             height: self.height,
             spacing: self.spacing,
@@ -254,7 +603,10 @@ impl Clone for App {
             contamination: self.contamination,
             octaves: self.octaves,
             cutoff: self.cutoff,
+            multiband: self.multiband,
+            multiband_levels: self.multiband_levels,
             mode: self.mode,
+            blend_space: self.blend_space,
             combine: self.combine,
             screen: self.screen,
             angle_scale: self.angle_scale,
@@ -262,11 +614,67 @@ impl Clone for App {
             radius_scale: self.radius_scale,
             radius_factor: self.radius_factor,
             sort_key: self.sort_key,
+            sort_reference: self.sort_reference,
+            custom_terms: self.custom_terms.clone(),
             sort_by: self.sort_by,
             row_sort_order: self.row_sort_order,
             col_sort_order: self.col_sort_order,
+            cell_layout: self.cell_layout,
+            cell_size: self.cell_size,
+            cell_random: self.cell_random,
+            melt_direction: self.melt_direction,
+            melt_order: self.melt_order,
+            melt_threshold: self.melt_threshold,
+            melt_length: self.melt_length,
+            melt_scale: self.melt_scale,
+            transfer_whole: self.transfer_whole,
+            sort_strength: self.sort_strength,
+            strength_noise: self.strength_noise,
+            strength_scale: self.strength_scale,
             grain_scale: self.grain_scale,
             grain_factor: self.grain_factor,
+            map_path: self.map_path.clone(),
+            extra_paths: self.extra_paths.clone(),
+            sort_mask: self.sort_mask,
+            anim_frames: self.anim_frames,
+            anim_delay: self.anim_delay,
+            key_kind: self.key_kind,
+            key_color: self.key_color,
+            key_threshold: self.key_threshold,
+            key_below: self.key_below,
+            key_tolerance: self.key_tolerance,
+            key_softness: self.key_softness,
+            key_spill: self.key_spill,
+            matte_threshold: self.matte_threshold,
+            matte_softness: self.matte_softness,
+            matte_blur: self.matte_blur,
+            matte_swap: self.matte_swap,
+            matte_show: self.matte_show,
+            hybrid_cutoff: self.hybrid_cutoff,
+            hybrid_detail: self.hybrid_detail,
+            hybrid_swap: self.hybrid_swap,
+            spectrum_mix: self.spectrum_mix,
+            spectrum_luma: self.spectrum_luma,
+            spectrum_swap: self.spectrum_swap,
+            reinhard_strength: self.reinhard_strength,
+            reinhard_luma: self.reinhard_luma,
+            histogram_space: self.histogram_space,
+            histogram_strength: self.histogram_strength,
+            palette_size: self.palette_size,
+            palette_method: self.palette_method,
+            palette_mapping: self.palette_mapping,
+            quantize: self.quantize,
+            quantize_palette: self.quantize_palette,
+            quantize_levels: self.quantize_levels,
+            quantize_colors: self.quantize_colors,
+            dither_kind: self.dither_kind,
+            dither_amount: self.dither_amount,
+            pixel_map: self.pixel_map.clone(),
+            loaded_map: self.loaded_map.clone(),
+            map_source: None,
+            extra_imgs: self.extra_imgs.clone(),
+            extra_outputs: Vec::new(),
+            palette: Vec::new(),
             texture: None,
             thumbnail_1: None,
             thumbnail_2: None,
@@ -275,6 +683,8 @@ impl Clone for App {
             img: self.img.clone(),
             drawing_in_progress: false,
             draw_receiver: None,
+            export_receiver: None,
+            quantizer: self.quantizer.clone(),
             status_message: String::new(),
             status_message_arc: None,
         }
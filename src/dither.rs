@@ -0,0 +1,369 @@
+//! Quantize the combined image to a few colors, evenly spaced levels per
+//! channel or a palette, dithered by error diffusion or a threshold map.
+
+use crate::core::{App, DitherKind, PaletteMethod, QuantizePalette};
+use crate::matrix::Matrix;
+use crate::swatches::extract;
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::sync::OnceLock;
+
+// The side of the tiled blue noise threshold map.
+const BLUE_NOISE_SIZE: usize = 64;
+
+// Offsets to the pixels that take a share of the error, and their weights.
+const FLOYD_STEINBERG: [(i32, i32, f32); 4] = [
+    (1, 0, 7.0 / 16.0),
+    (-1, 1, 3.0 / 16.0),
+    (0, 1, 5.0 / 16.0),
+    (1, 1, 1.0 / 16.0),
+];
+
+// Atkinson passes on only 3/4 of the error, which keeps the highlights and
+// shadows clean.
+const ATKINSON: [(i32, i32, f32); 6] = [
+    (1, 0, 1.0 / 8.0),
+    (2, 0, 1.0 / 8.0),
+    (-1, 1, 1.0 / 8.0),
+    (0, 1, 1.0 / 8.0),
+    (1, 1, 1.0 / 8.0),
+    (0, 2, 1.0 / 8.0),
+];
+
+const MONO: [[u8; 3]; 2] = [[0, 0, 0], [255, 255, 255]];
+
+// The paper, the process inks and their two ink overprints.
+const CMYK: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [0, 174, 239],
+    [236, 0, 140],
+    [255, 242, 0],
+    [35, 31, 32],
+    [237, 28, 36],
+    [0, 166, 81],
+    [46, 49, 146],
+];
+
+// The paper and the common risograph inks: black, blue, fluorescent pink,
+// yellow, teal and bright red.
+const RISO: [[u8; 3]; 7] = [
+    [255, 255, 255],
+    [0, 0, 0],
+    [0, 120, 191],
+    [255, 72, 176],
+    [255, 232, 0],
+    [0, 131, 138],
+    [241, 80, 96],
+];
+
+const GAME_BOY: [[u8; 3]; 4] = [[15, 56, 15], [48, 98, 48], [139, 172, 15], [155, 188, 15]];
+
+/// The colors an image is quantized to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Quantizer {
+    /// Evenly spaced levels in each RGB channel.
+    Levels(u32),
+    Palette(Vec<[u8; 3]>),
+}
+
+impl Quantizer {
+    fn nearest(&self, c: [f32; 3]) -> [f32; 3] {
+        match self {
+            Quantizer::Levels(n) => {
+                let step = 255.0 / (*n - 1) as f32;
+                c.map(|v| ((v / step).round() * step).clamp(0.0, 255.0))
+            }
+            Quantizer::Palette(colors) => {
+                let d = |p: &[f32; 3]| (0..3).map(|i| (p[i] - c[i]).powi(2)).sum::<f32>();
+                colors
+                    .iter()
+                    .map(|p| p.map(|v| v as f32))
+                    .min_by(|a, b| d(a).total_cmp(&d(b)))
+                    .unwrap()
+            }
+        }
+    }
+
+    // About the distance between neighboring colors, how far a threshold
+    // map pushes each pixel.
+    fn spread(&self) -> f32 {
+        match self {
+            Quantizer::Levels(n) => 255.0 / (*n - 1) as f32,
+            Quantizer::Palette(colors) => 255.0 / ((colors.len() as f32).cbrt().max(2.0) - 1.0),
+        }
+    }
+}
+
+/// The quantizer chosen in the app, a palette taken from image 2 is
+/// extracted by k-means.
+pub fn quantizer(app: &App, img_2: &RgbaImage) -> Quantizer {
+    let fixed = |colors: &[[u8; 3]]| Quantizer::Palette(colors.to_vec());
+    match app.quantize_palette {
+        QuantizePalette::Levels => Quantizer::Levels(app.quantize_levels.max(2)),
+        QuantizePalette::Image2 => Quantizer::Palette(extract(
+            img_2,
+            app.quantize_colors as usize,
+            PaletteMethod::KMeans,
+        )),
+        QuantizePalette::Mono => fixed(&MONO),
+        QuantizePalette::Cmyk => fixed(&CMYK),
+        QuantizePalette::Riso => fixed(&RISO),
+        QuantizePalette::GameBoy => fixed(&GAME_BOY),
+    }
+}
+
+/// Quantize the image, dithering it by the chosen method. `amount` scales the
+/// error diffused or the threshold offsets, at 0 every pixel just takes the
+/// nearest color. Alpha is left as it is.
+pub fn dither(img: &RgbaImage, q: &Quantizer, kind: DitherKind, amount: f32) -> RgbaImage {
+    if *q == Quantizer::Palette(Vec::new()) {
+        return img.clone();
+    }
+    match kind {
+        DitherKind::None => ordered(img, q, &Matrix::new(1, 1, vec![0.0]), 0.0),
+        DitherKind::FloydSteinberg => diffuse(img, q, &FLOYD_STEINBERG, amount),
+        DitherKind::Atkinson => diffuse(img, q, &ATKINSON, amount),
+        DitherKind::Bayer => ordered(img, q, &bayer(3), amount),
+        DitherKind::BlueNoise => ordered(img, q, blue_noise_map(), amount),
+    }
+}
+
+// Quantize each pixel in scan order, passing its error on to the pixels
+// that come after it.
+fn diffuse(img: &RgbaImage, q: &Quantizer, kernel: &[(i32, i32, f32)], amount: f32) -> RgbaImage {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let mut buf: Vec<[f32; 3]> = img
+        .pixels()
+        .map(|c| [c[0], c[1], c[2]].map(|v| v as f32))
+        .collect();
+    let mut out = RgbaImage::new(img.width(), img.height());
+    for (i, px) in out.pixels_mut().enumerate() {
+        let (x, y) = (i % w, i / w);
+        let old = buf[i].map(|v| v.clamp(0.0, 255.0));
+        let new = q.nearest(old);
+        let err: [f32; 3] = std::array::from_fn(|k| (old[k] - new[k]) * amount);
+        for &(dx, dy, weight) in kernel {
+            let (nx, ny) = (x as i32 + dx, y + dy as usize);
+            if nx >= 0 && (nx as usize) < w && ny < h {
+                for (v, e) in buf[ny * w + nx as usize].iter_mut().zip(err) {
+                    *v += e * weight;
+                }
+            }
+        }
+        *px = to_rgba(new, img.as_raw()[4 * i + 3]);
+    }
+    out
+}
+
+// Offset each pixel by the tiled threshold map before taking the nearest
+// color, so each pixel is independent and the pattern is stable.
+fn ordered(img: &RgbaImage, q: &Quantizer, thresholds: &Matrix<f32>, amount: f32) -> RgbaImage {
+    let spread = q.spread() * amount;
+    let mut out = RgbaImage::new(img.width(), img.height());
+    out.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let c = img.get_pixel(x, y);
+        let t = thresholds[y as usize % thresholds.height][x as usize % thresholds.width];
+        let new = q.nearest([c[0], c[1], c[2]].map(|v| (v as f32 + t * spread).clamp(0.0, 255.0)));
+        *px = to_rgba(new, c[3]);
+    });
+    out
+}
+
+fn to_rgba(c: [f32; 3], alpha: u8) -> Rgba<u8> {
+    let [r, g, b] = c.map(|v| v.round() as u8);
+    Rgba([r, g, b, alpha])
+}
+
+// Turn ranks from 0 to n - 1 into thresholds from -0.5 to 0.5.
+fn threshold(rank: usize, n: usize) -> f32 {
+    (rank as f32 + 0.5) / n as f32 - 0.5
+}
+
+// The Bayer matrix with side 2^bits, its rank found by interleaving the bits
+// of x ^ y and y in reverse.
+fn bayer(bits: u32) -> Matrix<f32> {
+    let size = 1usize << bits;
+    Matrix::generate(size, size, |x, y| {
+        let rank = (0..bits).fold(0, |v, b| {
+            (v << 2) | (((x ^ y) >> b & 1) << 1) | (y >> b & 1)
+        });
+        threshold(rank, size * size)
+    })
+}
+
+// The blue noise map is slow to build, so it is built on first use and
+// shared by every render after.
+fn blue_noise_map() -> &'static Matrix<f32> {
+    static MAP: OnceLock<Matrix<f32>> = OnceLock::new();
+    MAP.get_or_init(|| blue_noise(BLUE_NOISE_SIZE))
+}
+
+// A blue noise threshold map by Ulichney's void and cluster method. Points
+// are ranked by taking them out of the tightest clusters and putting them
+// into the largest voids of a pattern that wraps at the edges.
+fn blue_noise(size: usize) -> Matrix<f32> {
+    let n = size * size;
+    let sigma = 1.5f32;
+    // The energy a point adds at each offset.
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let (dx, dy) = (i % size, i / size);
+            let (dx, dy) = (dx.min(size - dx) as f32, dy.min(size - dy) as f32);
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let mut pattern = Pattern {
+        size,
+        on: vec![false; n],
+        energy: vec![0.0; n],
+        kernel,
+    };
+
+    let mut rng = fastrand::Rng::with_seed(17);
+    let ones = n / 10;
+    let mut count = 0;
+    while count < ones {
+        let i = rng.usize(..n);
+        if !pattern.on[i] {
+            pattern.toggle(i);
+            count += 1;
+        }
+    }
+    // Move points from the tightest cluster to the largest void until the
+    // point taken out is the best place to put it back.
+    for _ in 0..n {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+    let mut thinned = Pattern {
+        on: pattern.on.clone(),
+        energy: pattern.energy.clone(),
+        kernel: pattern.kernel.clone(),
+        ..pattern
+    };
+    for rank in (0..ones).rev() {
+        let cluster = thinned.tightest_cluster();
+        thinned.toggle(cluster);
+        ranks[cluster] = rank;
+    }
+    // The largest void is also the tightest cluster of the points not set,
+    // so filling voids ranks the rest of the map.
+    for rank in ones..n {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank;
+    }
+    Matrix::new(
+        size,
+        size,
+        ranks.into_iter().map(|r| threshold(r, n)).collect(),
+    )
+}
+
+struct Pattern {
+    size: usize,
+    on: Vec<bool>,
+    // The sum of the kernel over every point that is on.
+    energy: Vec<f32>,
+    kernel: Vec<f32>,
+}
+
+impl Pattern {
+    fn toggle(&mut self, i: usize) {
+        self.on[i] = !self.on[i];
+        let sign = if self.on[i] { 1.0 } else { -1.0 };
+        let (ix, iy) = (i % self.size, i / self.size);
+        for (j, e) in self.energy.iter_mut().enumerate() {
+            let dx = (j % self.size + self.size - ix) % self.size;
+            let dy = (j / self.size + self.size - iy) % self.size;
+            *e += sign * self.kernel[dy * self.size + dx];
+        }
+    }
+
+    fn tightest_cluster(&self) -> usize {
+        (0..self.on.len())
+            .filter(|&i| self.on[i])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    fn largest_void(&self) -> usize {
+        (0..self.on.len())
+            .filter(|&i| !self.on[i])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [DitherKind; 5] = [
+        DitherKind::None,
+        DitherKind::FloydSteinberg,
+        DitherKind::Atkinson,
+        DitherKind::Bayer,
+        DitherKind::BlueNoise,
+    ];
+
+    #[test]
+    fn threshold_map_test() {
+        // Every rank appears once in both maps.
+        for (map, n) in [(bayer(3), 64), (blue_noise(16), 256)] {
+            let mut ranks: Vec<usize> = map
+                .data
+                .iter()
+                .map(|t| ((t + 0.5) * n as f32 - 0.5).round() as usize)
+                .collect();
+            ranks.sort();
+            assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+        }
+        let b = bayer(1);
+        assert_eq!(b.data, [0, 2, 3, 1].map(|r| threshold(r, 4)));
+    }
+
+    #[test]
+    fn dither_test() {
+        let mut rng = fastrand::Rng::with_seed(21);
+        let img = RgbaImage::from_fn(24, 16, |_, _| {
+            Rgba([rng.u8(..), rng.u8(..), rng.u8(..), rng.u8(..)])
+        });
+        // Every pixel takes a palette color and keeps its alpha.
+        let q = Quantizer::Palette(RISO.to_vec());
+        for kind in KINDS {
+            let out = dither(&img, &q, kind, 1.0);
+            for (p, c) in out.pixels().zip(img.pixels()) {
+                assert!(RISO.contains(&[p[0], p[1], p[2]]));
+                assert_eq!(p[3], c[3]);
+            }
+        }
+
+        // With no dithering each channel rounds to the nearest level.
+        let out = dither(&img, &Quantizer::Levels(3), DitherKind::None, 1.0);
+        for (p, c) in out.pixels().zip(img.pixels()) {
+            for i in 0..3 {
+                let level = (c[i] as f32 / 127.5).round() * 127.5;
+                assert_eq!(p[i], level.round() as u8);
+            }
+        }
+
+        // Dithering a mid gray in black and white gives about half white.
+        let gray = RgbaImage::from_pixel(64, 64, Rgba([128, 128, 128, 255]));
+        let mono = Quantizer::Palette(MONO.to_vec());
+        for kind in &KINDS[1..] {
+            let out = dither(&gray, &mono, *kind, 1.0);
+            let white = out.pixels().filter(|p| p[0] == 255).count() as f32;
+            assert!((white / 4096.0 - 0.5).abs() < 0.05, "{:?}", kind);
+        }
+        let flat = dither(&gray, &mono, DitherKind::None, 1.0);
+        assert!(flat.pixels().all(|p| p[0] == 255));
+    }
+}
@@ -0,0 +1,154 @@
+//! Fourier transforms for the Spectrum mode. rustfft plans every length, so
+//! images of any size transform without padding.
+
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use rustfft::num_complex::Complex32;
+use rustfft::{FftDirection, FftPlanner};
+use wassily::prelude::palette::{FromColor, Oklab, Srgb};
+
+/// The 2D transform of a plane `width` values wide in row order, its rows in
+/// place and then its columns as the rows of its transpose. The result is left
+/// transposed, `height` values wide, so transforming it back with the sizes
+/// swapped restores the layout. Neither direction is normalized.
+pub fn fft_2d(
+    planner: &mut FftPlanner<f32>,
+    plane: &mut Vec<Complex32>,
+    width: usize,
+    height: usize,
+    direction: FftDirection,
+) {
+    fft_rows(planner, plane, width, direction);
+    let mut columns = vec![Complex32::default(); plane.len()];
+    columns
+        .par_chunks_mut(height)
+        .enumerate()
+        .for_each(|(x, column)| {
+            for (y, v) in column.iter_mut().enumerate() {
+                *v = plane[y * width + x];
+            }
+        });
+    fft_rows(planner, &mut columns, height, direction);
+    *plane = columns;
+}
+
+fn fft_rows(
+    planner: &mut FftPlanner<f32>,
+    plane: &mut [Complex32],
+    len: usize,
+    direction: FftDirection,
+) {
+    let fft = planner.plan_fft(len, direction);
+    plane.par_chunks_mut(len).for_each_init(
+        || vec![Complex32::default(); fft.get_inplace_scratch_len()],
+        |scratch, row| fft.process_with_scratch(row, scratch),
+    );
+}
+
+/// Recombine the Fourier phase of `phase` with the magnitude of `magnitude`,
+/// mixed with the phase image's own magnitude by `mix`. On luminance only the
+/// Oklab lightness is recombined and the phase image keeps its colors.
+pub fn spectral_swap(phase: &RgbaImage, magnitude: &RgbaImage, mix: f32, luma: bool) -> RgbaImage {
+    let (w, h) = (phase.width() as usize, phase.height() as usize);
+    let value = |c: Rgba<u8>, i: usize| {
+        if luma {
+            oklab(c).l
+        } else {
+            c[i] as f32 / 255.0
+        }
+    };
+    let mut planner = FftPlanner::new();
+    let scale = 1.0 / (w * h) as f32;
+    let planes: Vec<Vec<f32>> = (0..if luma { 1 } else { 3 })
+        .map(|i| {
+            let mut p = plane(phase, |c| value(c, i));
+            let mut m = plane(magnitude, |c| value(c, i));
+            fft_2d(&mut planner, &mut p, w, h, FftDirection::Forward);
+            fft_2d(&mut planner, &mut m, w, h, FftDirection::Forward);
+            p.par_iter_mut().zip(m.par_iter()).for_each(|(p, m)| {
+                let r = (1.0 - mix) * p.norm() + mix * m.norm();
+                *p = Complex32::from_polar(r, p.arg());
+            });
+            drop(m);
+            fft_2d(&mut planner, &mut p, h, w, FftDirection::Inverse);
+            p.into_par_iter().map(|c| c.re * scale).collect()
+        })
+        .collect();
+
+    let mut img = RgbaImage::new(w as u32, h as u32);
+    img.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let c = *phase.get_pixel(x, y);
+        let i = y as usize * w + x as usize;
+        let rgb = if luma {
+            let ok = oklab(c);
+            let rgb = Srgb::from_color(Oklab::new(planes[0][i], ok.a, ok.b));
+            [rgb.red, rgb.green, rgb.blue]
+        } else {
+            std::array::from_fn(|k| planes[k][i])
+        };
+        let rgb = rgb.map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+        *px = Rgba([rgb[0], rgb[1], rgb[2], c[3]]);
+    });
+    img
+}
+
+fn plane(img: &RgbaImage, f: impl Fn(Rgba<u8>) -> f32 + Sync) -> Vec<Complex32> {
+    img.par_pixels()
+        .map(|&c| Complex32::new(f(c), 0.0))
+        .collect()
+}
+
+fn oklab(c: Rgba<u8>) -> Oklab {
+    Oklab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fft_2d_test() {
+        let mut rng = fastrand::Rng::with_seed(5);
+        let mut planner = FftPlanner::new();
+        // Power of two sizes and others, each transformed and back.
+        for (w, h) in [(8, 4), (6, 4), (13, 7), (1, 5)] {
+            let x: Vec<Complex32> = (0..w * h)
+                .map(|_| Complex32::new(rng.f32() - 0.5, rng.f32() - 0.5))
+                .collect();
+            let mut buf = x.clone();
+            fft_2d(&mut planner, &mut buf, w, h, FftDirection::Forward);
+            // The first term is the sum of the plane.
+            let sum: Complex32 = x.iter().sum();
+            assert!((buf[0] - sum).norm() < 1e-4);
+            fft_2d(&mut planner, &mut buf, h, w, FftDirection::Inverse);
+            let n = (w * h) as f32;
+            assert!(buf.iter().zip(&x).all(|(a, b)| (*a / n - *b).norm() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn spectral_swap_test() {
+        let mut rng = fastrand::Rng::with_seed(9);
+        let mut random = || {
+            RgbaImage::from_fn(12, 10, |_, _| {
+                Rgba([rng.u8(..), rng.u8(..), rng.u8(..), 255])
+            })
+        };
+        let (a, b) = (random(), random());
+        let near = |x: &RgbaImage, y: &RgbaImage| {
+            x.pixels()
+                .zip(y.pixels())
+                .all(|(p, q)| (0..4).all(|i| p[i].abs_diff(q[i]) <= 1))
+        };
+        // With no mix, or the magnitude of the same image, nothing changes.
+        for luma in [false, true] {
+            assert!(near(&spectral_swap(&a, &b, 0.0, luma), &a));
+            assert!(near(&spectral_swap(&a, &a, 1.0, luma), &a));
+        }
+        // A flat image has all its energy at 0, so its phase with the
+        // magnitude of another image is no longer flat.
+        let flat = RgbaImage::from_pixel(12, 10, Rgba([128, 128, 128, 255]));
+        let out = spectral_swap(&flat, &a, 1.0, false);
+        assert!(out.pixels().any(|p| *p != out[(0, 0)]));
+    }
+}
@@ -0,0 +1,212 @@
+//! Luma, chroma and difference keys that choose between image 1 and image 2
+//! at each pixel, so image 2 shows through where image 1 is keyed out.
+
+use crate::core::{App, KeyKind};
+use crate::sortfns::luma;
+use image::{DynamicImage, GrayImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+use wassily::prelude::palette::{FromColor, Oklab, Srgb};
+
+/// Composite image 1 over image 2 wherever the key keeps image 1.
+pub fn key_fn(app: &App) -> impl Fn(Rgba<u8>, Rgba<u8>) -> Rgba<u8> + Sync {
+    let [r, g, b] = app.key_color;
+    let key = oklab(Rgba([r, g, b, 255]));
+    let kind = app.key_kind;
+    let threshold = app.key_threshold;
+    let below = app.key_below;
+    let tolerance = app.key_tolerance;
+    let softness = app.key_softness;
+    let spill = app.key_spill;
+    move |c1, c2| {
+        let kept = match kind {
+            KeyKind::Luma => {
+                let d = threshold - luma(c1);
+                ramp(if below { -d } else { d }, softness)
+            }
+            KeyKind::Chroma => {
+                let ok = oklab(c1);
+                let d = (ok.a - key.a).hypot(ok.b - key.b) * 255.0;
+                ramp(d - tolerance, softness)
+            }
+        };
+        let c1 = if kind == KeyKind::Chroma && spill > 0.0 {
+            suppress_spill(c1, key, spill)
+        } else {
+            c1
+        };
+        mix(c1, c2, kept)
+    }
+}
+
+/// The matte of where two photos of the same scene differ, white where their
+/// Oklab distance is above the threshold, softened and then blurred.
+pub fn difference_matte(app: &App, img_1: &RgbaImage, img_2: &RgbaImage) -> GrayImage {
+    let threshold = app.matte_threshold;
+    let softness = app.matte_softness;
+    let mut matte = GrayImage::new(img_1.width(), img_1.height());
+    matte.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let (a, b) = (oklab(*img_1.get_pixel(x, y)), oklab(*img_2.get_pixel(x, y)));
+        let d = ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt() * 255.0;
+        px[0] = (ramp(d - threshold, softness) * 255.0).round() as u8;
+    });
+    if app.matte_blur > 0.0 {
+        DynamicImage::ImageLuma8(matte)
+            .fast_blur(app.matte_blur)
+            .into_luma8()
+    } else {
+        matte
+    }
+}
+
+/// Image 1 where the matte is white over image 2 where it is black.
+pub fn matte_composite(img_1: &RgbaImage, img_2: &RgbaImage, matte: &GrayImage) -> RgbaImage {
+    let mut img = RgbaImage::new(img_1.width(), img_1.height());
+    img.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let t = matte.get_pixel(x, y)[0] as f32 / 255.0;
+        *px = mix(*img_1.get_pixel(x, y), *img_2.get_pixel(x, y), t);
+    });
+    img
+}
+
+fn mix(c1: Rgba<u8>, c2: Rgba<u8>, t: f32) -> Rgba<u8> {
+    Rgba(std::array::from_fn(|i| {
+        (c1[i] as f32 * t + c2[i] as f32 * (1.0 - t)).round() as u8
+    }))
+}
+
+// 0 at or below d = 0 rising to 1 over the softness, a hard step when there
+// is no softness.
+fn ramp(d: f32, softness: f32) -> f32 {
+    if softness <= 0.0 {
+        return if d > 0.0 { 1.0 } else { 0.0 };
+    }
+    (d / softness).clamp(0.0, 1.0)
+}
+
+// Pull the chroma of the color away from the key's hue, removing the cast a
+// colored backdrop leaves on the edges of the subject.
+fn suppress_spill(c: Rgba<u8>, key: Oklab, amount: f32) -> Rgba<u8> {
+    let chroma = key.a.hypot(key.b);
+    if chroma < 1e-4 {
+        return c;
+    }
+    let (ka, kb) = (key.a / chroma, key.b / chroma);
+    let mut ok = oklab(c);
+    let along = ok.a * ka + ok.b * kb;
+    if along <= 0.0 {
+        return c;
+    }
+    ok.a -= amount * along * ka;
+    ok.b -= amount * along * kb;
+    let rgb: Srgb<u8> = Srgb::from_color(ok).into_format();
+    Rgba([rgb.red, rgb.green, rgb.blue, c[3]])
+}
+
+fn oklab(c: Rgba<u8>) -> Oklab {
+    Oklab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([200, 30, 30, 255]);
+    const GREEN: Rgba<u8> = Rgba([20, 230, 40, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[test]
+    fn luma_key_test() {
+        let app = App {
+            key_kind: KeyKind::Luma,
+            key_threshold: 128.0,
+            key_softness: 0.0,
+            ..App::default()
+        };
+        let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+        let key = key_fn(&app);
+        assert_eq!(key(black, BLUE), black);
+        assert_eq!(key(white, BLUE), BLUE);
+        let key = key_fn(&App {
+            key_below: true,
+            ..app
+        });
+        assert_eq!(key(black, BLUE), BLUE);
+        assert_eq!(key(white, BLUE), white);
+
+        // A soft key mixes the two images near the threshold.
+        let key = key_fn(&App {
+            key_kind: KeyKind::Luma,
+            key_threshold: 128.0,
+            key_softness: 100.0,
+            ..App::default()
+        });
+        let gray = Rgba([78, 78, 78, 255]);
+        let mixed = key(gray, BLUE);
+        assert!(mixed[0] > 0 && mixed[0] < 78 && mixed[2] > 78);
+    }
+
+    #[test]
+    fn chroma_key_test() {
+        let app = App {
+            key_kind: KeyKind::Chroma,
+            key_color: [0, 255, 0],
+            key_tolerance: 30.0,
+            key_softness: 10.0,
+            key_spill: 0.0,
+            ..App::default()
+        };
+        let key = key_fn(&app);
+        assert_eq!(key(GREEN, BLUE), BLUE);
+        assert_eq!(key(RED, BLUE), RED);
+
+        // Spill suppression takes the green cast out of what is kept, and
+        // leaves colors with none of the key's hue alone.
+        let tinted = Rgba([150, 190, 140, 255]);
+        let key = key_fn(&App {
+            key_spill: 1.0,
+            ..app
+        });
+        let out = key(tinted, BLUE);
+        assert!(out[1] < tinted[1]);
+        assert_eq!(key(RED, BLUE), RED);
+    }
+
+    #[test]
+    fn difference_matte_test() {
+        let background = RgbaImage::from_pixel(8, 6, RED);
+        let mut moved = background.clone();
+        for y in 2..4 {
+            for x in 2..5 {
+                moved.put_pixel(x, y, GREEN);
+            }
+        }
+        let app = App {
+            matte_threshold: 20.0,
+            matte_softness: 0.0,
+            matte_blur: 0.0,
+            ..App::default()
+        };
+        let matte = difference_matte(&app, &moved, &background);
+        for (x, y, px) in matte.enumerate_pixels() {
+            let inside = (2..5).contains(&x) && (2..4).contains(&y);
+            assert_eq!(px[0], if inside { 255 } else { 0 });
+        }
+        // The subject of image 1 is kept on the background of image 2.
+        let other = RgbaImage::from_pixel(8, 6, BLUE);
+        let img = matte_composite(&moved, &other, &matte);
+        assert_eq!(*img.get_pixel(3, 3), GREEN);
+        assert_eq!(*img.get_pixel(0, 0), BLUE);
+
+        // Blurring feathers the edge of the matte.
+        let blurred = difference_matte(
+            &App {
+                matte_blur: 1.0,
+                ..app
+            },
+            &moved,
+            &background,
+        );
+        let edge = blurred.get_pixel(1, 3)[0];
+        assert!(edge > 0 && edge < 255);
+    }
+}
@@ -1,8 +1,36 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
+mod animate;
 mod app;
 pub use core::App;
 mod art;
+mod bands;
+mod cells;
 mod core;
+mod dither;
+mod fft;
+mod key;
+mod mapfile;
 mod matrix;
 mod sortfns;
+mod swatches;
+mod transfer;
+
+/// The sort engine, public only so the benchmarks can reach it.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::art::{
+        pixel_map_column, pixel_map_row, pixel_unsort, sort_keys, sort_map_column, sort_map_image,
+        sort_map_row,
+    };
+    pub use crate::core::{ImgGrid, KeyGrid, SortOrder};
+    use image::Rgba;
+    use std::sync::Arc;
+
+    pub type SortKeyFn = Arc<dyn Fn(u32, u32, Rgba<u8>) -> f32 + Send + Sync>;
+
+    /// The hue sort key, which converts every pixel to HSL.
+    pub fn hue_key() -> SortKeyFn {
+        Arc::new(|_, _, c| crate::sortfns::hue(c))
+    }
+}
@@ -0,0 +1,207 @@
+//! Save, load and resample pixel maps so a sort can be reused on other images.
+
+use crate::core::ImgGrid;
+use crate::matrix::Matrix;
+use image::{ImageBuffer, Rgb};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"MXLPXMAP";
+
+// A map with no pixels has no locations to clamp to, so it is rejected
+// wherever one could come in.
+fn empty_map() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "pixel map is empty")
+}
+
+pub type UvImage = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+/// Write a pixel map as a header followed by a little endian u16 pair per pixel.
+pub fn write_map<W: Write>(px_map: &ImgGrid, mut w: W) -> Result<(), Box<dyn Error>> {
+    if px_map.width > u16::MAX as usize + 1 || px_map.height > u16::MAX as usize + 1 {
+        return Err("pixel map is too large to save".into());
+    }
+    w.write_all(MAGIC)?;
+    w.write_all(&(px_map.width as u32).to_le_bytes())?;
+    w.write_all(&(px_map.height as u32).to_le_bytes())?;
+    for &(x, y) in &px_map.data {
+        w.write_all(&(x as u16).to_le_bytes())?;
+        w.write_all(&(y as u16).to_le_bytes())?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Read a pixel map written by `write_map`.
+pub fn read_map<R: Read>(mut r: R) -> Result<ImgGrid, Box<dyn Error>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("not a pixel map file".into());
+    }
+    let mut word = [0u8; 4];
+    r.read_exact(&mut word)?;
+    let width = u32::from_le_bytes(word) as usize;
+    r.read_exact(&mut word)?;
+    let height = u32::from_le_bytes(word) as usize;
+    if width > u16::MAX as usize + 1 || height > u16::MAX as usize + 1 {
+        return Err("pixel map header is corrupt".into());
+    }
+    if width == 0 || height == 0 {
+        return Err(empty_map().into());
+    }
+    // Read a row at a time so a corrupt header fails at the end of the
+    // stream rather than on a huge allocation.
+    let mut data = Vec::new();
+    let mut row = vec![0u8; width * 4];
+    for _ in 0..height {
+        r.read_exact(&mut row)?;
+        data.extend(row.chunks_exact(4).map(|c| {
+            let x = u16::from_le_bytes([c[0], c[1]]) as usize;
+            let y = u16::from_le_bytes([c[2], c[3]]) as usize;
+            (x.min(width - 1), y.min(height - 1))
+        }));
+    }
+    Ok(Matrix::new(width, height, data))
+}
+
+/// Encode a pixel map as a 16 bit image, red is x and green is y, both
+/// normalized to the full range so the map is independent of its size.
+pub fn to_uv_image(px_map: &ImgGrid) -> UvImage {
+    let sx = 65535.0 / px_map.width.saturating_sub(1).max(1) as f32;
+    let sy = 65535.0 / px_map.height.saturating_sub(1).max(1) as f32;
+    ImageBuffer::from_fn(px_map.width as u32, px_map.height as u32, |x, y| {
+        let (mx, my) = px_map[y as usize][x as usize];
+        Rgb([
+            (mx as f32 * sx).round() as u16,
+            (my as f32 * sy).round() as u16,
+            0,
+        ])
+    })
+}
+
+/// Decode a pixel map from a uv image made by `to_uv_image`.
+pub fn from_uv_image(img: &UvImage) -> ImgGrid {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let sx = w.saturating_sub(1).max(1) as f32 / 65535.0;
+    let sy = h.saturating_sub(1).max(1) as f32 / 65535.0;
+    Matrix::generate(w, h, |x, y| {
+        let p = img.get_pixel(x as u32, y as u32);
+        let mx = (p[0] as f32 * sx).round() as usize;
+        let my = (p[1] as f32 * sy).round() as usize;
+        (mx.min(w - 1), my.min(h - 1))
+    })
+}
+
+/// Resample a pixel map to a new size with nearest neighbor sampling,
+/// scaling the stored locations to match.
+pub fn resample_map(px_map: &ImgGrid, width: usize, height: usize) -> io::Result<ImgGrid> {
+    if [px_map.width, px_map.height, width, height].contains(&0) {
+        return Err(empty_map());
+    }
+    if px_map.width == width && px_map.height == height {
+        return Ok(Matrix::new(width, height, px_map.data.clone()));
+    }
+    let sx = px_map.width as f32 / width as f32;
+    let sy = px_map.height as f32 / height as f32;
+    Ok(Matrix::generate(width, height, |x, y| {
+        // Keep the offset within the source pixel so that an identity map
+        // stays an identity map.
+        let (fx, fy) = ((x as f32 + 0.5) * sx, (y as f32 + 0.5) * sy);
+        let (x0, y0) = (fx as usize, fy as usize);
+        let (mx, my) = px_map[y0.min(px_map.height - 1)][x0.min(px_map.width - 1)];
+        let mx = ((mx as f32 + fx.fract()) / sx) as usize;
+        let my = ((my as f32 + fy.fract()) / sy) as usize;
+        (mx.min(width - 1), my.min(height - 1))
+    }))
+}
+
+/// Save a pixel map, as a uv image if the extension is png and otherwise in
+/// the binary format.
+pub fn save(px_map: &ImgGrid, path: &Path) -> Result<(), Box<dyn Error>> {
+    if path.extension().is_some_and(|e| e == "png") {
+        to_uv_image(px_map).save(path)?;
+    } else {
+        write_map(px_map, BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
+
+/// Load a pixel map saved by `save`.
+pub fn load(path: &Path) -> Result<ImgGrid, Box<dyn Error>> {
+    if path.extension().is_some_and(|e| e == "png") {
+        let img = image::open(path)?.into_rgb16();
+        if img.width() == 0 || img.height() == 0 {
+            return Err(empty_map().into());
+        }
+        Ok(from_uv_image(&img))
+    } else {
+        read_map(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_map() -> ImgGrid {
+        Matrix::generate(7, 5, |x, y| ((x * 3 + y) % 7, (y * 2 + x) % 5))
+    }
+
+    #[test]
+    fn binary_roundtrip_test() {
+        let m = test_map();
+        let mut bytes = Vec::new();
+        write_map(&m, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), 16 + 7 * 5 * 4);
+        assert_eq!(read_map(bytes.as_slice()).unwrap(), m);
+    }
+
+    #[test]
+    fn bad_magic_test() {
+        assert!(read_map(&b"NOTAMAP!\0\0\0\0\0\0\0\0"[..]).is_err());
+        // A header claiming the largest map with no data after it.
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(65536u32.to_le_bytes());
+        bytes.extend(65536u32.to_le_bytes());
+        assert!(read_map(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn empty_map_test() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(5u32.to_le_bytes());
+        let e = read_map(bytes.as_slice()).unwrap_err();
+        let e = e.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let empty = Matrix::new(0, 0, Vec::new());
+        assert!(resample_map(&empty, 7, 5).is_err());
+        assert!(resample_map(&test_map(), 0, 5).is_err());
+    }
+
+    #[test]
+    fn uv_roundtrip_test() {
+        let m = test_map();
+        assert_eq!(from_uv_image(&to_uv_image(&m)), m);
+    }
+
+    #[test]
+    fn resample_test() {
+        let m = test_map();
+        assert_eq!(resample_map(&m, 7, 5).unwrap(), m);
+        let big = resample_map(&m, 14, 10).unwrap();
+        assert_eq!(big.width, 14);
+        assert_eq!(big.height, 10);
+        let (mx, my) = m[1][3];
+        assert_eq!(big[2][6], (mx * 2, my * 2));
+        assert_eq!(big[3][7], (mx * 2 + 1, my * 2 + 1));
+        let identity = Matrix::generate(7, 5, |x, y| (x, y));
+        for (w, h) in [(3, 2), (14, 10), (20, 4)] {
+            let resampled = resample_map(&identity, w, h).unwrap();
+            assert_eq!(resampled, Matrix::generate(w, h, |x, y| (x, y)));
+        }
+    }
+}
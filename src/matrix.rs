@@ -2,6 +2,7 @@
 
 #![allow(dead_code)]
 use num_traits::{AsPrimitive, One, Zero};
+use rayon::prelude::*;
 use std::ops::{Index, IndexMut};
 
 #[derive(Debug)]
@@ -113,6 +114,26 @@ where
     }
 }
 
+impl<T> Matrix<T>
+where
+    T: Copy + Send + Sync,
+{
+    /// Swap rows and columns, filling the new matrix in parallel.
+    pub fn transpose(&self) -> Self {
+        let (w, h) = (self.width, self.height);
+        let mut data = Vec::with_capacity(w * h);
+        (0..w * h)
+            .into_par_iter()
+            .map(|i| self.data[(i % h) * w + i / h])
+            .collect_into_vec(&mut data);
+        Self {
+            width: h,
+            height: w,
+            data,
+        }
+    }
+}
+
 impl<T> Matrix<T>
 where
     T: Zero + Clone,
@@ -196,6 +217,16 @@ mod tests {
         assert_eq!(m.get_ref(0, 3), None);
     }
 
+    #[test]
+    fn transpose_test() {
+        let m = Matrix::generate(2, 3, |i, j| (i, j));
+        let t = m.transpose();
+        assert_eq!(t.width, 3);
+        assert_eq!(t.height, 2);
+        assert_eq!(t.data, vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]);
+        assert_eq!(t.transpose(), m);
+    }
+
     #[test]
     fn put_test() {
         let mut m = Matrix::generate(2, 3, |i, j| (i, j));
@@ -1,64 +1,222 @@
+use crate::core::{App, SortFeature, SortKey};
 use image::*;
 use std::cmp::{max, min};
+use std::sync::Arc;
+use wassily::prelude::palette::{FromColor, Hsv, Lab, Oklab, Oklch, Srgb};
+use wassily::prelude::*;
 
-pub(crate) type SortFn = fn(Rgba<u8>) -> i16;
+// Sort keys are floats so wide-range products and signed channel differences
+// never overflow. The key is given the position of the pixel and its color.
+pub(crate) type SortFn = Arc<dyn Fn(u32, u32, Rgba<u8>) -> f32 + Send + Sync>;
 
-pub(crate) fn luma(c: Rgba<u8>) -> i16 {
-    c.to_luma()[0] as i16
+// Look up the sort function for the app's sort key.
+pub(crate) fn sort_fn(app: &App) -> SortFn {
+    match app.sort_key {
+        SortKey::Lightness => color_key(luma),
+        SortKey::Hue => color_key(hue),
+        SortKey::Saturation => color_key(sat),
+        SortKey::MaxRgb => color_key(max_rgb),
+        SortKey::MinRgb => color_key(min_rgb),
+        SortKey::Rg => color_key(r_g),
+        SortKey::Gb => color_key(g_b),
+        SortKey::Br => color_key(b_r),
+        SortKey::WrappedHue => color_key(wrapped_hue),
+        SortKey::HueSat => color_key(hue_sat),
+        SortKey::LumaSat => color_key(luma_sat),
+        SortKey::Chroma => color_key(chroma),
+        SortKey::OklabL => color_key(oklab_l),
+        SortKey::OklchChroma => color_key(oklch_chroma),
+        SortKey::OklchHue => color_key(oklch_hue),
+        SortKey::LabL => color_key(lab_l),
+        SortKey::LabA => color_key(lab_a),
+        SortKey::LabB => color_key(lab_b),
+        SortKey::HsvValue => color_key(hsv_value),
+        SortKey::Luminance => color_key(luminance),
+        SortKey::Distance => {
+            let [r, g, b] = app.sort_reference;
+            let reference = oklab(Rgba([r, g, b, 255]));
+            Arc::new(move |_, _, c| distance(c, reference))
+        }
+        SortKey::Custom => custom_key(app),
+    }
+}
+
+// A sort function that only looks at the color of the pixel.
+fn color_key(f: fn(Rgba<u8>) -> f32) -> SortFn {
+    Arc::new(move |_, _, c| f(c))
+}
+
+// A weighted sum of features, each scaled to 0..255 and optionally quantized.
+fn custom_key(app: &App) -> SortFn {
+    let terms = app.custom_terms.clone();
+    let (w, h) = (app.width as f32, app.height as f32);
+    let opts = NoiseOpts::default().scales(5.0).width(w).height(h);
+    let nf = Fbm::<Perlin>::default()
+        .set_seed(37)
+        .set_octaves(app.octaves);
+    Arc::new(move |x, y, c| {
+        terms
+            .iter()
+            .map(|term| {
+                let v = match term.feature {
+                    SortFeature::X => x as f32 / (w - 1.0).max(1.0) * 255.0,
+                    SortFeature::Y => y as f32 / (h - 1.0).max(1.0) * 255.0,
+                    SortFeature::Noise => {
+                        (noise2d(&nf, &opts, x as f32, y as f32).clamp(-1.0, 1.0) + 1.0) * 127.5
+                    }
+                    feature => color_feature(feature, c),
+                };
+                term.weight * quantize(v, term.levels)
+            })
+            .sum()
+    })
+}
+
+// The value of a color feature scaled to 0..255.
+fn color_feature(feature: SortFeature, c: Rgba<u8>) -> f32 {
+    match feature {
+        SortFeature::Red => c[0] as f32,
+        SortFeature::Green => c[1] as f32,
+        SortFeature::Blue => c[2] as f32,
+        SortFeature::Alpha => c[3] as f32,
+        SortFeature::Luma => luma(c),
+        SortFeature::Hue => hue(c),
+        SortFeature::Saturation => sat(c),
+        SortFeature::Lightness => hsl(c[0], c[1], c[2]).2 * 255.0,
+        SortFeature::OklchL => oklab_l(c),
+        SortFeature::OklchChroma => oklch_chroma(c),
+        SortFeature::OklchHue => oklch_hue(c),
+        SortFeature::X | SortFeature::Y | SortFeature::Noise => 0.0,
+    }
 }
 
-pub(crate) fn hue(c: Rgba<u8>) -> i16 {
+// Snap a value in 0..255 to the bottom of one of `levels` equal bands,
+// 0 or 1 levels leaves the value unchanged.
+pub(crate) fn quantize(v: f32, levels: u32) -> f32 {
+    if levels <= 1 {
+        return v;
+    }
+    let n = levels as f32;
+    (v / 256.0 * n).floor().clamp(0.0, n - 1.0) * 256.0 / n
+}
+
+pub(crate) fn luma(c: Rgba<u8>) -> f32 {
+    c.to_luma()[0] as f32
+}
+
+pub(crate) fn hue(c: Rgba<u8>) -> f32 {
     let hsl = hsl(c[0], c[1], c[2]);
-    let hue = hsl.0 / 360.0 * 255.0;
-    hue as i16
+    hsl.0 / 360.0 * 255.0
 }
 
-pub(crate) fn sat(c: Rgba<u8>) -> i16 {
+pub(crate) fn sat(c: Rgba<u8>) -> f32 {
     let hsl = hsl(c[0], c[1], c[2]);
-    let sat = hsl.1 * 255.0;
-    sat as i16
+    hsl.1 * 255.0
 }
 
-pub(crate) fn max_rgb(c: Rgba<u8>) -> i16 {
+pub(crate) fn max_rgb(c: Rgba<u8>) -> f32 {
     let max = max(max(c[0], c[1]), c[2]);
-    max as i16
+    max as f32
 }
 
-pub(crate) fn min_rgb(c: Rgba<u8>) -> i16 {
+pub(crate) fn min_rgb(c: Rgba<u8>) -> f32 {
     let min = min(min(c[0], c[1]), c[2]);
-    min as i16
+    min as f32
 }
 
-pub(crate) fn r_g(c: Rgba<u8>) -> i16 {
-    (c[0] - c[1]) as i16
+pub(crate) fn r_g(c: Rgba<u8>) -> f32 {
+    c[0] as f32 - c[1] as f32
 }
 
-pub(crate) fn g_b(c: Rgba<u8>) -> i16 {
-    (c[1] - c[2]) as i16
+pub(crate) fn g_b(c: Rgba<u8>) -> f32 {
+    c[1] as f32 - c[2] as f32
 }
 
-pub(crate) fn b_r(c: Rgba<u8>) -> i16 {
-    (c[2] - c[0]) as i16
+pub(crate) fn b_r(c: Rgba<u8>) -> f32 {
+    c[2] as f32 - c[0] as f32
 }
 
-pub(crate) fn wrapped_hue(c: Rgba<u8>) -> i16 {
+pub(crate) fn wrapped_hue(c: Rgba<u8>) -> f32 {
     let hsl = hsl(c[0], c[1], c[2]);
     let h = f32::min(hsl.0, 360.0 - hsl.0);
-    (h / 360.0 * 255.0) as i16
+    h / 360.0 * 255.0
 }
 
-pub(crate) fn hue_sat(c: Rgba<u8>) -> i16 {
+pub(crate) fn hue_sat(c: Rgba<u8>) -> f32 {
     hue(c) * sat(c)
 }
 
-pub(crate) fn luma_sat(c: Rgba<u8>) -> i16 {
+pub(crate) fn luma_sat(c: Rgba<u8>) -> f32 {
     luma(c) * sat(c)
 }
 
-pub(crate) fn chroma(c: Rgba<u8>) -> i16 {
+pub(crate) fn chroma(c: Rgba<u8>) -> f32 {
     let mx = max(max(c[0], c[1]), c[2]);
     let mn = min(min(c[0], c[1]), c[2]);
-    (mx - mn) as i16
+    (mx - mn) as f32
+}
+
+// Oklab lightness scaled to 0..255.
+pub(crate) fn oklab_l(c: Rgba<u8>) -> f32 {
+    oklab(c).l * 255.0
+}
+
+// Oklch chroma scaled by 255, sRGB colors stay below about 83.
+pub(crate) fn oklch_chroma(c: Rgba<u8>) -> f32 {
+    Oklch::from_color(oklab(c)).chroma * 255.0
+}
+
+// Oklch hue scaled to 0..255, grays have hue 0.
+pub(crate) fn oklch_hue(c: Rgba<u8>) -> f32 {
+    let lch = Oklch::from_color(oklab(c));
+    if lch.chroma < 1e-4 {
+        return 0.0;
+    }
+    lch.hue.into_positive_degrees() / 360.0 * 255.0
+}
+
+// CIELAB L* scaled from 0..100 to 0..255.
+pub(crate) fn lab_l(c: Rgba<u8>) -> f32 {
+    lab(c).l * 2.55
+}
+
+// CIELAB a*, green (negative) to red (positive).
+pub(crate) fn lab_a(c: Rgba<u8>) -> f32 {
+    lab(c).a
+}
+
+// CIELAB b*, blue (negative) to yellow (positive).
+pub(crate) fn lab_b(c: Rgba<u8>) -> f32 {
+    lab(c).b
+}
+
+pub(crate) fn hsv_value(c: Rgba<u8>) -> f32 {
+    Hsv::from_color(srgb(c)).value * 255.0
+}
+
+// Relative luminance in linear light scaled to 0..255.
+pub(crate) fn luminance(c: Rgba<u8>) -> f32 {
+    let lin = srgb(c).into_linear::<f32>();
+    (0.2126 * lin.red + 0.7152 * lin.green + 0.0722 * lin.blue) * 255.0
+}
+
+// Euclidean distance in Oklab from a reference color, scaled by 255.
+pub(crate) fn distance(c: Rgba<u8>, reference: Oklab) -> f32 {
+    let ok = oklab(c);
+    let (dl, da, db) = (ok.l - reference.l, ok.a - reference.a, ok.b - reference.b);
+    (dl * dl + da * da + db * db).sqrt() * 255.0
+}
+
+fn srgb(c: Rgba<u8>) -> Srgb {
+    Srgb::new(c[0], c[1], c[2]).into_format()
+}
+
+fn oklab(c: Rgba<u8>) -> Oklab {
+    Oklab::from_color(srgb(c))
+}
+
+fn lab(c: Rgba<u8>) -> Lab {
+    Lab::from_color(srgb(c))
 }
 
 fn hsl(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
@@ -98,3 +256,174 @@ fn hsl(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
 
     (h_degrees, s, l)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::SortTerm;
+    use rayon::prelude::*;
+
+    const KEYS: [SortKey; 22] = [
+        SortKey::Lightness,
+        SortKey::Hue,
+        SortKey::Saturation,
+        SortKey::MaxRgb,
+        SortKey::MinRgb,
+        SortKey::Rg,
+        SortKey::Gb,
+        SortKey::Br,
+        SortKey::WrappedHue,
+        SortKey::HueSat,
+        SortKey::LumaSat,
+        SortKey::Chroma,
+        SortKey::OklabL,
+        SortKey::OklchChroma,
+        SortKey::OklchHue,
+        SortKey::LabL,
+        SortKey::LabA,
+        SortKey::LabB,
+        SortKey::HsvValue,
+        SortKey::Luminance,
+        SortKey::Distance,
+        SortKey::Custom,
+    ];
+
+    // The range every sort key must stay within.
+    fn bounds(key: SortKey) -> (f32, f32) {
+        match key {
+            SortKey::Rg | SortKey::Gb | SortKey::Br => (-255.0, 255.0),
+            SortKey::WrappedHue => (0.0, 127.5),
+            SortKey::HueSat | SortKey::LumaSat => (0.0, 255.0 * 255.0),
+            SortKey::LabA | SortKey::LabB => (-128.0, 128.0),
+            SortKey::Distance => (0.0, 255.0 * 1.5),
+            SortKey::Custom => (0.0, 255.0),
+            _ => (0.0, 255.0),
+        }
+    }
+
+    // Check a property for every color in the RGB cube.
+    fn for_rgb_cube<F>(f: F)
+    where
+        F: Fn(Rgba<u8>) + Sync,
+    {
+        (0..=255u8).into_par_iter().for_each(|r| {
+            for g in 0..=255u8 {
+                for b in 0..=255u8 {
+                    f(Rgba([r, g, b, 255]));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn rgb_cube_bounds_test() {
+        for key in KEYS {
+            let f = sort_fn(&App {
+                sort_key: key,
+                ..Default::default()
+            });
+            let (lo, hi) = bounds(key);
+            for_rgb_cube(|c| {
+                let k = f(0, 0, c);
+                assert!(k.is_finite(), "{key:?} of {c:?} is {k}");
+                assert!(lo - 1e-3 <= k && k <= hi + 1e-3, "{key:?} of {c:?} is {k}");
+            });
+        }
+    }
+
+    #[test]
+    fn rgb_cube_differences_test() {
+        for_rgb_cube(|c| {
+            let (r, g, b) = (c[0] as f32, c[1] as f32, c[2] as f32);
+            assert_eq!(r_g(c), r - g);
+            assert_eq!(g_b(c), g - b);
+            assert_eq!(b_r(c), b - r);
+            assert_eq!(r_g(c) + g_b(c) + b_r(c), 0.0);
+        });
+    }
+
+    #[test]
+    fn perceptual_test() {
+        for_rgb_cube(|c| assert!((hsv_value(c) - max_rgb(c)).abs() < 1e-3));
+        let black = Rgba([0, 0, 0, 255]);
+        let white = Rgba([255, 255, 255, 255]);
+        for f in [oklab_l, lab_l, luminance] {
+            assert!(f(black).abs() < 1e-2);
+            assert!((f(white) - 255.0).abs() < 1e-2);
+        }
+        let gray = Rgba([128, 128, 128, 255]);
+        assert_eq!(oklch_hue(gray), 0.0);
+        assert!(oklch_chroma(gray) < 0.1);
+        let d = sort_fn(&App {
+            sort_key: SortKey::Distance,
+            sort_reference: [10, 200, 30],
+            ..Default::default()
+        });
+        assert!(d(0, 0, Rgba([10, 200, 30, 255])) < 1e-3);
+        assert!(d(0, 0, Rgba([10, 200, 30, 255])) < d(0, 0, Rgba([10, 190, 30, 255])));
+    }
+
+    #[test]
+    fn custom_test() {
+        let term = |feature, weight, levels| SortTerm {
+            feature,
+            weight,
+            levels,
+        };
+        let app = App {
+            sort_key: SortKey::Custom,
+            width: 101,
+            height: 51,
+            custom_terms: vec![
+                term(SortFeature::Luma, 0.7, 0),
+                term(SortFeature::Hue, 0.3, 0),
+            ],
+            ..Default::default()
+        };
+        let f = sort_fn(&app);
+        for_rgb_cube(|c| {
+            let k = 0.7 * luma(c) + 0.3 * hue(c);
+            assert!((f(0, 0, c) - k).abs() < 1e-3);
+        });
+
+        let app = App {
+            custom_terms: vec![term(SortFeature::X, 1.0, 0), term(SortFeature::Y, -2.0, 0)],
+            ..app
+        };
+        let f = sort_fn(&app);
+        let c = Rgba([0, 0, 0, 255]);
+        assert_eq!(f(0, 0, c), 0.0);
+        assert_eq!(f(100, 0, c), 255.0);
+        assert_eq!(f(100, 50, c), -255.0);
+
+        let app = App {
+            custom_terms: vec![term(SortFeature::Noise, 1.0, 0)],
+            ..app
+        };
+        let f = sort_fn(&app);
+        for (x, y) in [(0, 0), (37, 12), (100, 50)] {
+            let k = f(x, y, c);
+            assert!((0.0..=255.0).contains(&k));
+        }
+    }
+
+    #[test]
+    fn quantize_test() {
+        assert_eq!(quantize(200.0, 0), 200.0);
+        assert_eq!(quantize(200.0, 1), 200.0);
+        assert_eq!(quantize(0.0, 4), 0.0);
+        assert_eq!(quantize(63.0, 4), 0.0);
+        assert_eq!(quantize(64.0, 4), 64.0);
+        assert_eq!(quantize(255.0, 4), 192.0);
+        assert_eq!(quantize(255.0, 256), 255.0);
+    }
+
+    #[test]
+    fn wide_range_test() {
+        let c = Rgba([255, 0, 0, 255]);
+        assert_eq!(sat(c), 255.0);
+        assert_eq!(luma_sat(c), luma(c) * 255.0);
+        let c = Rgba([0, 0, 255, 255]);
+        assert!(hue_sat(c) > i16::MAX as f32);
+    }
+}
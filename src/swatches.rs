@@ -0,0 +1,371 @@
+//! Extract a palette from an image in Oklab, recolor images with it and save
+//! it as a GIMP palette, an Adobe swatch exchange file or json.
+
+use crate::core::{PaletteMapping, PaletteMethod};
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use wassily::prelude::palette::{FromColor, Oklab, Srgb};
+
+// Sample at most this many pixels, chosen at random, when extracting a
+// palette. Random pixels don't line up with columns of the image the way a
+// fixed step does.
+const MAX_SAMPLES: usize = 1 << 16;
+
+const KMEANS_ITERATIONS: usize = 16;
+
+// The number of lightness levels used to rank pixels.
+const BINS: usize = 1024;
+
+/// Up to `n` colors that represent the image, darkest first.
+pub fn extract(img: &RgbaImage, n: usize, method: PaletteMethod) -> Vec<[u8; 3]> {
+    let len = img.pixels().len();
+    let points: Vec<[f32; 3]> = if len <= MAX_SAMPLES {
+        img.pixels().map(|&c| oklab(c)).collect()
+    } else {
+        let mut rng = fastrand::Rng::with_seed(31);
+        let w = img.width() as usize;
+        (0..MAX_SAMPLES)
+            .map(|_| {
+                let i = rng.usize(..len);
+                oklab(*img.get_pixel((i % w) as u32, (i / w) as u32))
+            })
+            .collect()
+    };
+    if points.is_empty() || n == 0 {
+        return Vec::new();
+    }
+    let centers = median_cut(&points, n);
+    let mut centers = match method {
+        PaletteMethod::MedianCut => centers,
+        PaletteMethod::KMeans => kmeans(&points, centers),
+    };
+    centers.sort_by(|a, b| a[0].total_cmp(&b[0]));
+    let mut palette: Vec<[u8; 3]> = centers.into_iter().map(to_rgb).collect();
+    palette.dedup();
+    palette
+}
+
+/// Replace each pixel with the nearest palette color in Oklab, or by its
+/// lightness rank, so the darkest share of the pixels takes the darkest color.
+pub fn recolor(img: &RgbaImage, palette: &[[u8; 3]], mapping: PaletteMapping) -> RgbaImage {
+    if palette.is_empty() {
+        return img.clone();
+    }
+    let mut labs: Vec<[f32; 3]> = palette
+        .iter()
+        .map(|&[r, g, b]| oklab(Rgba([r, g, b, 255])))
+        .collect();
+    labs.sort_by(|a, b| a[0].total_cmp(&b[0]));
+    // Only the rank mapping needs the lightness of the whole image.
+    let ranks = match mapping {
+        PaletteMapping::Nearest => Vec::new(),
+        PaletteMapping::Rank => lightness_ranks(img),
+    };
+    let n = labs.len();
+    let mut out = RgbaImage::new(img.width(), img.height());
+    out.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let c = *img.get_pixel(x, y);
+        let lab = oklab(c);
+        let i = match mapping {
+            PaletteMapping::Nearest => nearest(&labs, lab),
+            PaletteMapping::Rank => ((ranks[bin(lab[0])] * n as f64) as usize).min(n - 1),
+        };
+        let [r, g, b] = to_rgb(labs[i]);
+        *px = Rgba([r, g, b, c[3]]);
+    });
+    out
+}
+
+// Split the box of points with the widest range at its median along that
+// axis until there are `n` boxes, and return the mean of each.
+fn median_cut(points: &[[f32; 3]], n: usize) -> Vec<[f32; 3]> {
+    let mut boxes = vec![points.to_vec()];
+    while boxes.len() < n {
+        let (i, axis, range) = boxes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let (axis, range) = widest_axis(b);
+                (i, axis, range)
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+            .unwrap();
+        if range <= 0.0 {
+            break;
+        }
+        let mut b = boxes.swap_remove(i);
+        b.sort_by(|p, q| p[axis].total_cmp(&q[axis]));
+        let upper = b.split_off(b.len() / 2);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+fn widest_axis(points: &[[f32; 3]]) -> (usize, f32) {
+    (0..3)
+        .map(|axis| {
+            let (lo, hi) = points.iter().fold((f32::MAX, f32::MIN), |(lo, hi), p| {
+                (lo.min(p[axis]), hi.max(p[axis]))
+            });
+            (axis, hi - lo)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+// Refine the centers with Lloyd's algorithm, a center with no points keeps
+// its place.
+fn kmeans(points: &[[f32; 3]], mut centers: Vec<[f32; 3]>) -> Vec<[f32; 3]> {
+    for _ in 0..KMEANS_ITERATIONS {
+        let k = centers.len();
+        let (sums, counts) = points
+            .par_iter()
+            .fold(
+                || (vec![[0.0f64; 3]; k], vec![0usize; k]),
+                |(mut sums, mut counts), p| {
+                    let i = nearest(&centers, *p);
+                    (0..3).for_each(|j| sums[i][j] += p[j] as f64);
+                    counts[i] += 1;
+                    (sums, counts)
+                },
+            )
+            .reduce(
+                || (vec![[0.0f64; 3]; k], vec![0usize; k]),
+                |(mut sums, mut counts), (s, c)| {
+                    for (sum, s) in sums.iter_mut().zip(&s) {
+                        (0..3).for_each(|j| sum[j] += s[j]);
+                    }
+                    for (count, c) in counts.iter_mut().zip(&c) {
+                        *count += c;
+                    }
+                    (sums, counts)
+                },
+            );
+        let next: Vec<[f32; 3]> = centers
+            .iter()
+            .zip(sums.iter().zip(&counts))
+            .map(|(&c, (s, &n))| {
+                if n == 0 {
+                    c
+                } else {
+                    s.map(|v| (v / n as f64) as f32)
+                }
+            })
+            .collect();
+        if next == centers {
+            break;
+        }
+        centers = next;
+    }
+    centers
+}
+
+fn nearest(labs: &[[f32; 3]], p: [f32; 3]) -> usize {
+    let d = |q: &[f32; 3]| (0..3).map(|i| (p[i] - q[i]).powi(2)).sum::<f32>();
+    labs.iter()
+        .enumerate()
+        .min_by(|a, b| d(a.1).total_cmp(&d(b.1)))
+        .map(|(i, _)| i)
+        .unwrap()
+}
+
+fn mean(points: &[[f32; 3]]) -> [f32; 3] {
+    let n = points.len().max(1) as f64;
+    let sum = points.iter().fold([0.0f64; 3], |s, p| {
+        [s[0] + p[0] as f64, s[1] + p[1] as f64, s[2] + p[2] as f64]
+    });
+    sum.map(|v| (v / n) as f32)
+}
+
+fn bin(l: f32) -> usize {
+    (l.clamp(0.0, 1.0) * (BINS - 1) as f32).round() as usize
+}
+
+// The middle rank, from 0 to 1, of the pixels at each level of Oklab
+// lightness.
+fn lightness_ranks(img: &RgbaImage) -> Vec<f64> {
+    let counts = img
+        .par_pixels()
+        .fold(
+            || vec![0u64; BINS],
+            |mut counts, &c| {
+                counts[bin(oklab(c)[0])] += 1;
+                counts
+            },
+        )
+        .reduce(
+            || vec![0u64; BINS],
+            |a, b| a.iter().zip(&b).map(|(a, b)| a + b).collect(),
+        );
+    let n = counts.iter().sum::<u64>().max(1) as f64;
+    counts
+        .iter()
+        .scan(0, |below, &k| {
+            let rank = (*below as f64 + k as f64 / 2.0) / n;
+            *below += k;
+            Some(rank)
+        })
+        .collect()
+}
+
+fn oklab(c: Rgba<u8>) -> [f32; 3] {
+    let lab = Oklab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>());
+    [lab.l, lab.a, lab.b]
+}
+
+fn to_rgb(lab: [f32; 3]) -> [u8; 3] {
+    let rgb = Srgb::from_color(Oklab::new(lab[0], lab[1], lab[2]));
+    [rgb.red, rgb.green, rgb.blue].map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Write the palette as a GIMP palette.
+pub fn write_gpl<W: Write>(palette: &[[u8; 3]], mut w: W) -> Result<(), Box<dyn Error>> {
+    writeln!(w, "GIMP Palette")?;
+    writeln!(w, "Name: Mixel")?;
+    writeln!(w, "Columns: {}", palette.len().min(16))?;
+    writeln!(w, "#")?;
+    for &c in palette {
+        writeln!(w, "{:3} {:3} {:3}\t{}", c[0], c[1], c[2], hex(c))?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write the palette as an Adobe swatch exchange file, one RGB color entry
+/// per color named by its hex code.
+pub fn write_ase<W: Write>(palette: &[[u8; 3]], mut w: W) -> Result<(), Box<dyn Error>> {
+    w.write_all(b"ASEF")?;
+    w.write_all(&1u16.to_be_bytes())?;
+    w.write_all(&0u16.to_be_bytes())?;
+    w.write_all(&(palette.len() as u32).to_be_bytes())?;
+    for &c in palette {
+        // The name is a null terminated UTF-16 string.
+        let name: Vec<u16> = hex(c).encode_utf16().chain([0]).collect();
+        let len = 2 + name.len() * 2 + 4 + 12 + 2;
+        w.write_all(&1u16.to_be_bytes())?;
+        w.write_all(&(len as u32).to_be_bytes())?;
+        w.write_all(&(name.len() as u16).to_be_bytes())?;
+        for u in name {
+            w.write_all(&u.to_be_bytes())?;
+        }
+        w.write_all(b"RGB ")?;
+        for v in c {
+            w.write_all(&(v as f32 / 255.0).to_be_bytes())?;
+        }
+        // A normal, not a global or spot, color.
+        w.write_all(&2u16.to_be_bytes())?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write the palette as a json array of hex codes.
+pub fn write_json<W: Write>(palette: &[[u8; 3]], mut w: W) -> Result<(), Box<dyn Error>> {
+    let codes: Vec<String> = palette.iter().map(|&c| hex(c)).collect();
+    serde_json::to_writer_pretty(&mut w, &codes)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Save the palette in the format given by the extension, gpl, ase or json.
+pub fn save(palette: &[[u8; 3]], path: &Path) -> Result<(), Box<dyn Error>> {
+    let w = BufWriter::new(File::create(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("ase") => write_ase(palette, w),
+        Some("json") => write_json(palette, w),
+        _ => write_gpl(palette, w),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Blue, red and yellow, darkest first.
+    const COLORS: [[u8; 3]; 3] = [[20, 30, 160], [200, 40, 40], [230, 200, 40]];
+
+    // Stripes, half of them blue and a quarter each red and yellow.
+    fn stripes() -> RgbaImage {
+        RgbaImage::from_fn(12, 8, |x, _| {
+            let [r, g, b] = COLORS[[0, 1, 0, 2][x as usize % 4]];
+            Rgba([r, g, b, 255])
+        })
+    }
+
+    #[test]
+    fn extract_test() {
+        let img = stripes();
+        for method in [PaletteMethod::MedianCut, PaletteMethod::KMeans] {
+            let palette = extract(&img, 3, method);
+            assert_eq!(palette.len(), 3);
+            // The palette holds each color, darkest first.
+            for (p, e) in palette.iter().zip(&COLORS) {
+                assert!((0..3).all(|i| p[i].abs_diff(e[i]) <= 1), "{:?}", palette);
+            }
+            // Asking for more colors than there are gives each color once.
+            assert_eq!(extract(&img, 8, method).len(), 3);
+        }
+
+        // Sampling a large image still sees every column of the stripes.
+        let wide = RgbaImage::from_fn(512, 256, |x, y| *img.get_pixel(x % 12, y % 8));
+        assert_eq!(extract(&wide, 3, PaletteMethod::MedianCut).len(), 3);
+    }
+
+    #[test]
+    fn recolor_test() {
+        let img = stripes();
+        let palette = [[0, 0, 0], [128, 128, 128], [255, 255, 255]];
+        let near = recolor(&img, &palette, PaletteMapping::Nearest);
+        for (p, q) in near.pixels().zip(img.pixels()) {
+            assert!(palette.contains(&[p[0], p[1], p[2]]));
+            assert_eq!(p[3], q[3]);
+        }
+        // By rank the darkest pixels take the darkest color and the
+        // brightest the brightest.
+        let ranked = recolor(&img, &palette, PaletteMapping::Rank);
+        let lightness = |p: &Rgba<u8>| oklab(*p)[0];
+        for (p, q) in ranked.pixels().zip(img.pixels()) {
+            for (p2, q2) in ranked.pixels().zip(img.pixels()) {
+                if lightness(q) < lightness(q2) {
+                    assert!(p[0] <= p2[0]);
+                }
+            }
+        }
+        assert!(ranked.pixels().any(|p| p[0] == 0));
+        assert!(ranked.pixels().any(|p| p[0] == 255));
+    }
+
+    #[test]
+    fn export_test() {
+        let palette = [[255, 0, 0], [0, 128, 255]];
+        let mut gpl = Vec::new();
+        write_gpl(&palette, &mut gpl).unwrap();
+        let gpl = String::from_utf8(gpl).unwrap();
+        assert!(gpl.starts_with("GIMP Palette\n"));
+        assert!(gpl.contains("255   0   0\t#ff0000\n"));
+        assert!(gpl.contains("  0 128 255\t#0080ff\n"));
+
+        let mut ase = Vec::new();
+        write_ase(&palette, &mut ase).unwrap();
+        assert_eq!(&ase[..4], b"ASEF");
+        assert_eq!(u32::from_be_bytes(ase[8..12].try_into().unwrap()), 2);
+        // Each block has a 6 byte header and an 8 character name.
+        assert_eq!(ase.len(), 12 + 2 * (6 + 2 + 16 + 4 + 12 + 2));
+        assert_eq!(&ase[12..14], &[0, 1]);
+        assert_eq!(f32::from_be_bytes(ase[40..44].try_into().unwrap()), 1.0);
+
+        let mut json = Vec::new();
+        write_json(&palette, &mut json).unwrap();
+        let codes: Vec<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(codes, ["#ff0000", "#0080ff"]);
+    }
+}
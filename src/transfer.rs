@@ -0,0 +1,290 @@
+//! Transferring the color statistics of image 2 onto image 1, either the mean
+//! and spread of each channel or its whole histogram.
+
+use crate::core::HistogramSpace;
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use wassily::prelude::palette::{FromColor, Oklab, Oklch, Srgb};
+
+// The number of levels each channel is binned into for histogram matching.
+const BINS: usize = 1024;
+
+// Oklch chroma is divided by this to bring it into 0..1.
+const MAX_CHROMA: f32 = 0.4;
+
+/// Reinhard's color transfer, shift and scale each Oklab channel of image 1
+/// to the mean and standard deviation of image 2. With `luma_only` just the
+/// lightness is matched, and `strength` mixes from image 1 to the result.
+pub fn reinhard(img_1: &RgbaImage, img_2: &RgbaImage, strength: f32, luma_only: bool) -> RgbaImage {
+    let (mean_1, std_1) = stats(img_1);
+    let (mean_2, std_2) = stats(img_2);
+    let channels = if luma_only { 1 } else { 3 };
+    let mut img = RgbaImage::new(img_1.width(), img_1.height());
+    img.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let c = *img_1.get_pixel(x, y);
+        let mut lab = to_array(oklab(c));
+        for (i, v) in lab.iter_mut().enumerate().take(channels) {
+            let scale = if std_1[i] > 1e-6 {
+                std_2[i] / std_1[i]
+            } else {
+                1.0
+            };
+            let matched = mean_2[i] + (*v - mean_1[i]) * scale;
+            *v += (matched - *v) * strength;
+        }
+        *px = to_rgba(lab, c[3]);
+    });
+    img
+}
+
+/// Remap each channel of image 1 so its cumulative histogram matches that of
+/// image 2, in RGB, on Oklch lightness only or on each Oklch channel.
+/// `strength` mixes from image 1 to the matched image.
+pub fn match_histograms(
+    img_1: &RgbaImage,
+    img_2: &RgbaImage,
+    space: HistogramSpace,
+    strength: f32,
+) -> RgbaImage {
+    let count = if space == HistogramSpace::Luma { 1 } else { 3 };
+    let maps: Vec<Vec<f32>> = (0..count)
+        .map(|i| {
+            let cdf_1 = cdf(img_1, |c| channels(c, space)[i]);
+            let cdf_2 = cdf(img_2, |c| channels(c, space)[i]);
+            cdf_1
+                .iter()
+                .map(|&p| {
+                    let j = cdf_2.partition_point(|&q| q < p).min(BINS - 1);
+                    j as f32 / (BINS - 1) as f32
+                })
+                .collect()
+        })
+        .collect();
+    let mut img = RgbaImage::new(img_1.width(), img_1.height());
+    img.par_enumerate_pixels_mut().for_each(|(x, y, px)| {
+        let c = *img_1.get_pixel(x, y);
+        let mut vs = channels(c, space);
+        for (i, (v, map)) in vs.iter_mut().enumerate().zip(&maps) {
+            let t = map[bin(*v)];
+            *v = if space == HistogramSpace::Oklch && i == 2 {
+                mix_hue(*v, t, strength)
+            } else {
+                *v + (t - *v) * strength
+            };
+        }
+        *px = from_channels(vs, c, space);
+    });
+    img
+}
+
+// The channels of a color that are matched, each scaled into 0..1.
+fn channels(c: Rgba<u8>, space: HistogramSpace) -> [f32; 3] {
+    match space {
+        HistogramSpace::Rgb => [c[0], c[1], c[2]].map(|v| v as f32 / 255.0),
+        HistogramSpace::Luma | HistogramSpace::Oklch => {
+            let lch = Oklch::from_color(oklab(c));
+            [
+                lch.l,
+                lch.chroma / MAX_CHROMA,
+                lch.hue.into_positive_degrees() / 360.0,
+            ]
+        }
+    }
+}
+
+fn from_channels(vs: [f32; 3], c: Rgba<u8>, space: HistogramSpace) -> Rgba<u8> {
+    match space {
+        HistogramSpace::Rgb => {
+            let [r, g, b] = vs.map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+            Rgba([r, g, b, c[3]])
+        }
+        HistogramSpace::Luma | HistogramSpace::Oklch => {
+            let lch = Oklch::new(vs[0], vs[1] * MAX_CHROMA, vs[2] * 360.0);
+            to_rgba(to_array(Oklab::from_color(lch)), c[3])
+        }
+    }
+}
+
+// Mix between two hues scaled into 0..1 the short way round the circle.
+fn mix_hue(v: f32, t: f32, strength: f32) -> f32 {
+    let d = (t - v + 0.5).rem_euclid(1.0) - 0.5;
+    (v + d * strength).rem_euclid(1.0)
+}
+
+fn bin(v: f32) -> usize {
+    (v.clamp(0.0, 1.0) * (BINS - 1) as f32).round() as usize
+}
+
+// The cumulative histogram of one channel of the image, rising to 1.
+fn cdf<F>(img: &RgbaImage, f: F) -> Vec<f64>
+where
+    F: Fn(Rgba<u8>) -> f32 + Sync,
+{
+    let counts = img
+        .par_pixels()
+        .fold(
+            || vec![0u64; BINS],
+            |mut counts, &c| {
+                counts[bin(f(c))] += 1;
+                counts
+            },
+        )
+        .reduce(
+            || vec![0u64; BINS],
+            |a, b| a.iter().zip(&b).map(|(a, b)| a + b).collect(),
+        );
+    let n = counts.iter().sum::<u64>().max(1) as f64;
+    counts
+        .iter()
+        .scan(0, |sum, &k| {
+            *sum += k;
+            Some(*sum as f64 / n)
+        })
+        .collect()
+}
+
+// The mean and standard deviation of each Oklab channel of the image.
+fn stats(img: &RgbaImage) -> ([f32; 3], [f32; 3]) {
+    let n = (img.width() * img.height()).max(1) as f64;
+    let (sum, sum_sq) = img
+        .par_pixels()
+        .map(|&c| {
+            let lab = to_array(oklab(c)).map(|v| v as f64);
+            (lab, lab.map(|v| v * v))
+        })
+        .reduce(
+            || ([0.0; 3], [0.0; 3]),
+            |(s, q), (s2, q2)| {
+                (
+                    std::array::from_fn(|i| s[i] + s2[i]),
+                    std::array::from_fn(|i| q[i] + q2[i]),
+                )
+            },
+        );
+    let mean = sum.map(|s| s / n);
+    let std = std::array::from_fn(|i| (sum_sq[i] / n - mean[i] * mean[i]).max(0.0).sqrt() as f32);
+    (mean.map(|m| m as f32), std)
+}
+
+fn oklab(c: Rgba<u8>) -> Oklab {
+    Oklab::from_color(Srgb::new(c[0], c[1], c[2]).into_format::<f32>())
+}
+
+fn to_array(c: Oklab) -> [f32; 3] {
+    [c.l, c.a, c.b]
+}
+
+fn to_rgba(lab: [f32; 3], alpha: u8) -> Rgba<u8> {
+    let rgb = Srgb::from_color(Oklab::new(lab[0], lab[1], lab[2]));
+    let [r, g, b] =
+        [rgb.red, rgb.green, rgb.blue].map(|v| (v * 255.0).round().clamp(0.0, 255.0) as u8);
+    Rgba([r, g, b, alpha])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A random image with its colors kept away from the edges of the gamut.
+    fn test_image(seed: u64, lo: u8, hi: u8) -> RgbaImage {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        RgbaImage::from_fn(16, 12, |_, _| {
+            Rgba([rng.u8(lo..hi), rng.u8(lo..hi), rng.u8(lo..hi), 255])
+        })
+    }
+
+    #[test]
+    fn reinhard_test() {
+        let a = test_image(3, 60, 140);
+        let b = test_image(4, 110, 190);
+        let near =
+            |x: [f32; 3], y: [f32; 3], tol: f32| x.iter().zip(y).all(|(x, y)| (x - y).abs() < tol);
+
+        // A full transfer takes on the statistics of image 2.
+        let out = reinhard(&a, &b, 1.0, false);
+        let ((mean_out, std_out), (mean_b, std_b)) = (stats(&out), stats(&b));
+        assert!(near(mean_out, mean_b, 0.005));
+        assert!(near(std_out, std_b, 0.005));
+
+        // No strength leaves image 1 as it is.
+        let same = reinhard(&a, &b, 0.0, false);
+        assert!(same
+            .pixels()
+            .zip(a.pixels())
+            .all(|(p, q)| (0..4).all(|i| p[i].abs_diff(q[i]) <= 1)));
+
+        // Matching luminance only keeps the colors of image 1.
+        let luma = reinhard(&a, &b, 1.0, true);
+        let (mean_luma, _) = stats(&luma);
+        let (mean_a, _) = stats(&a);
+        assert!((mean_luma[0] - mean_b[0]).abs() < 0.005);
+        assert!(near(
+            [0.0, mean_luma[1], mean_luma[2]],
+            [0.0, mean_a[1], mean_a[2]],
+            0.01
+        ));
+    }
+
+    fn sorted_channel(img: &RgbaImage, i: usize) -> Vec<u8> {
+        let mut vs: Vec<u8> = img.pixels().map(|p| p[i]).collect();
+        vs.sort();
+        vs
+    }
+
+    #[test]
+    fn match_histograms_test() {
+        let a = test_image(5, 0, 120);
+        let b = test_image(6, 100, 255);
+        // Matching in RGB gives each channel the distribution of image 2.
+        let out = match_histograms(&a, &b, HistogramSpace::Rgb, 1.0);
+        for i in 0..3 {
+            let (o, t) = (sorted_channel(&out, i), sorted_channel(&b, i));
+            // Ties in image 1 map to the last of the matching values.
+            let diffs: Vec<u8> = o.iter().zip(&t).map(|(o, t)| o.abs_diff(*t)).collect();
+            assert!(diffs.iter().all(|&d| d <= 8));
+            assert!(diffs.iter().map(|&d| d as f32).sum::<f32>() / (diffs.len() as f32) < 2.0);
+        }
+
+        // No strength, or matching an image to itself, changes nothing.
+        for space in [
+            HistogramSpace::Rgb,
+            HistogramSpace::Luma,
+            HistogramSpace::Oklch,
+        ] {
+            for (img, target, strength) in [(&a, &b, 0.0), (&a, &a, 1.0)] {
+                let out = match_histograms(img, target, space, strength);
+                assert!(out
+                    .pixels()
+                    .zip(img.pixels())
+                    .all(|(p, q)| (0..4).all(|i| p[i].abs_diff(q[i]) <= 1)));
+            }
+        }
+
+        // Matching lightness only brightens the darker image.
+        let luma = match_histograms(&a, &b, HistogramSpace::Luma, 1.0);
+        let (mean_luma, _) = stats(&luma);
+        let (mean_b, _) = stats(&b);
+        assert!((mean_luma[0] - mean_b[0]).abs() < 0.01);
+    }
+
+    #[test]
+    fn mix_hue_test() {
+        // The distance between two hues round the circle.
+        let gap = |a: f32, b: f32| {
+            let d = (a - b).rem_euclid(1.0);
+            d.min(1.0 - d)
+        };
+        // Halfway between hues on either side of the wrap stays near 0, not
+        // at the opposite color.
+        for (v, t) in [(0.99, 0.01), (0.01, 0.99), (0.9, 0.2)] {
+            let mid = mix_hue(v, t, 0.5);
+            assert!((0.0..=1.0).contains(&mid));
+            assert!((gap(mid, v) - gap(mid, t)).abs() < 1e-5);
+            assert!(gap(mid, v) <= 0.25);
+        }
+        assert!(gap(mix_hue(0.99, 0.01, 0.5), 0.0) < 1e-5);
+        // Hues that don't wrap mix in a straight line.
+        assert!((mix_hue(0.2, 0.4, 0.5) - 0.3).abs() < 1e-5);
+        assert!((mix_hue(0.7, 0.1, 1.0) - 0.1).abs() < 1e-5);
+    }
+}